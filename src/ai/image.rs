@@ -0,0 +1,309 @@
+use std::{error::Error, fmt::Display, io::Cursor};
+
+use image::{
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits, codecs::jpeg::JpegEncoder,
+    imageops::FilterType, metadata::Orientation,
+};
+
+/// The formats images may be sent in.
+pub const FORMATS: [ImageFormat; 5] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::WebP,
+    ImageFormat::Gif,
+    ImageFormat::Bmp,
+];
+/// The widest or tallest image that may be decoded, in pixels.
+pub const MAX_DECODED_DIMENSION: u32 = 16_384;
+// Decoding an image may take as much memory as an RGBA image with this many times the area of
+// the largest image the model takes, enough for the photos of most cameras.
+const MAX_DECODE_AREA: u64 = 4;
+// The quality of re-encoded JPEG images, out of 100.
+const JPEG_QUALITY: u8 = 85;
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+// The PNG chunks that make up the image and its colors. The others are metadata, or hints.
+const PNG_CHUNKS: [&[u8]; 10] = [
+    b"IHDR", b"PLTE", b"IDAT", b"IEND", b"tRNS", b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageError {
+    UnsupportedFormat,
+    /// The image is larger than [`MAX_DECODED_DIMENSION`], or would take too much memory to
+    /// decode.
+    TooLarge,
+    Invalid,
+}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::UnsupportedFormat => {
+                write!(f, "The image must be a PNG, JPEG, WebP, GIF or BMP image.")
+            }
+            ImageError::TooLarge => write!(
+                f,
+                "The image is over {} pixels wide or tall, or has too many pixels.",
+                MAX_DECODED_DIMENSION
+            ),
+            ImageError::Invalid => write!(f, "The image could not be decoded."),
+        }
+    }
+}
+
+impl Error for ImageError {}
+
+impl From<image::ImageError> for ImageError {
+    fn from(err: image::ImageError) -> Self {
+        match err {
+            image::ImageError::Limits(_) => ImageError::TooLarge,
+            image::ImageError::Unsupported(_) => ImageError::UnsupportedFormat,
+            _ => ImageError::Invalid,
+        }
+    }
+}
+
+/// Normalizes an image before it goes to a model: turns it upright following its EXIF
+/// orientation, scales it down to fit `max_dimension` and drops its metadata. PNG and JPEG images
+/// that are already upright and small enough keep their bytes, only without their metadata.
+/// Others are re-encoded, as a PNG if they were one or have transparency, and as a JPEG otherwise.
+/// Animated images keep their first frame.
+pub fn normalize(bytes: &[u8], max_dimension: u32) -> Result<Vec<u8>, ImageError> {
+    let mut reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|_| ImageError::Invalid)?;
+    let Some(format) = reader.format().filter(|format| FORMATS.contains(format)) else {
+        return Err(ImageError::UnsupportedFormat);
+    };
+    let max_alloc = u64::from(max_dimension).pow(2) * 4 * MAX_DECODE_AREA;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DECODED_DIMENSION);
+    limits.max_image_height = Some(MAX_DECODED_DIMENSION);
+    limits.max_alloc = Some(max_alloc);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder()?;
+    // Not every decoder counts the decoded image against the limits, so its size is checked too.
+    if decoder.total_bytes() > max_alloc {
+        return Err(ImageError::TooLarge);
+    }
+    let orientation = decoder.orientation()?;
+    let (width, height) = decoder.dimensions();
+    // Decoded even when its bytes are kept, so that only valid images go to the model.
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    if orientation == Orientation::NoTransforms
+        && width <= max_dimension
+        && height <= max_dimension
+        && let Some(stripped) = strip_metadata(bytes, format)
+    {
+        return Ok(stripped);
+    }
+    image.apply_orientation(orientation);
+
+    if image.width() > max_dimension || image.height() > max_dimension {
+        // Keeps the aspect ratio, fitting the image in a `max_dimension` square.
+        image = image.resize(max_dimension, max_dimension, FilterType::CatmullRom);
+    }
+
+    let mut encoded = Vec::new();
+    if format == ImageFormat::Png || image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+            .write_to(&mut Cursor::new(&mut encoded), ImageFormat::Png)?;
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY))?;
+    }
+    Ok(encoded)
+}
+
+// The bytes of an image without its metadata, for the formats whose metadata can be dropped
+// without decoding the image. `None` for the other formats, or if the image's structure is off.
+fn strip_metadata(bytes: &[u8], format: ImageFormat) -> Option<Vec<u8>> {
+    match format {
+        ImageFormat::Png => strip_png(bytes),
+        ImageFormat::Jpeg => strip_jpeg(bytes),
+        _ => None,
+    }
+}
+
+// Keeps the chunks of a PNG image listed in `PNG_CHUNKS`. Each chunk carries its own checksum, so
+// the kept ones are copied as they are.
+fn strip_png(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks = bytes.strip_prefix(PNG_SIGNATURE)?;
+    let mut stripped = PNG_SIGNATURE.to_vec();
+    loop {
+        // The length of the data, followed by the type, the data and a checksum.
+        let length = u32::from_be_bytes(chunks.get(..4)?.try_into().ok()?) as usize;
+        let chunk = chunks.get(..length.checked_add(12)?)?;
+        let chunk_type = &chunk[4..8];
+        if PNG_CHUNKS.contains(&chunk_type) {
+            stripped.extend_from_slice(chunk);
+        }
+        if chunk_type == b"IEND" {
+            return Some(stripped);
+        }
+        chunks = &chunks[chunk.len()..];
+    }
+}
+
+// Drops the application segments and comments of a JPEG image, which hold its EXIF, XMP and other
+// metadata, except for those that tell how to read its colors: JFIF, the ICC profile and Adobe's.
+fn strip_jpeg(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut segments = bytes.strip_prefix(&[0xFF, 0xD8])?;
+    let mut stripped = vec![0xFF, 0xD8];
+    loop {
+        let [0xFF, marker, ..] = *segments else {
+            return None;
+        };
+        match marker {
+            // Fill bytes before a marker.
+            0xFF => {
+                segments = &segments[1..];
+                continue;
+            }
+            // The end of the image, past which anything else, such as the other pictures of an
+            // MPO file, is dropped.
+            0xD9 => {
+                stripped.extend_from_slice(&segments[..2]);
+                return Some(stripped);
+            }
+            // Markers without a segment.
+            0x01 | 0xD0..=0xD7 => {
+                stripped.extend_from_slice(&segments[..2]);
+                segments = &segments[2..];
+                continue;
+            }
+            _ => {}
+        }
+
+        // The marker, followed by the length of the segment, which counts itself.
+        let length = u16::from_be_bytes([*segments.get(2)?, *segments.get(3)?]) as usize;
+        let segment = segments.get(..length.checked_add(2)?)?;
+        let data = &segment[4..];
+        let keep = match marker {
+            0xE0 => data.starts_with(b"JFIF\0"),
+            0xE2 => data.starts_with(b"ICC_PROFILE\0"),
+            0xEE => data.starts_with(b"Adobe"),
+            0xE0..=0xEF | 0xFE => false,
+            _ => true,
+        };
+        if keep {
+            stripped.extend_from_slice(segment);
+        }
+        segments = &segments[segment.len()..];
+
+        // A scan is followed by its entropy-coded data, which runs up to the next marker, as
+        // 0xFF bytes in the data are followed by a zero or a restart marker.
+        if marker == 0xDA {
+            let end = segments.windows(2).position(|pair| {
+                pair[0] == 0xFF && pair[1] != 0 && !(0xD0..=0xD7).contains(&pair[1])
+            })?;
+            stripped.extend_from_slice(&segments[..end]);
+            segments = &segments[end..];
+        }
+    }
+}
+
+/// The MIME type of an image, sniffed from its first bytes.
+pub fn mime_type(bytes: &[u8]) -> Option<&'static str> {
+    image::guess_format(bytes)
+        .ok()
+        .filter(|format| FORMATS.contains(format))
+        .map(|format| format.to_mime_type())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn encode(image: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn keeps_upright_small_images_without_their_metadata() {
+        let png = encode(
+            DynamicImage::ImageRgb8(RgbImage::from_pixel(30, 20, Rgb([200, 10, 10]))),
+            ImageFormat::Png,
+        );
+        assert_eq!(normalize(&png, 200).unwrap(), png);
+
+        let jpeg = encode(
+            DynamicImage::ImageRgb8(RgbImage::from_pixel(30, 20, Rgb([200, 10, 10]))),
+            ImageFormat::Jpeg,
+        );
+        // A comment, right after the start of the image.
+        let mut commented = jpeg[..2].to_vec();
+        commented.extend_from_slice(b"\xFF\xFE\x00\x07Hello");
+        commented.extend_from_slice(&jpeg[2..]);
+        assert_eq!(normalize(&commented, 200).unwrap(), jpeg);
+    }
+
+    #[test]
+    fn keeps_png_images_as_png() {
+        let png = encode(
+            DynamicImage::ImageRgb8(RgbImage::from_pixel(400, 100, Rgb([200, 10, 10]))),
+            ImageFormat::Png,
+        );
+
+        let normalized = normalize(&png, 200).unwrap();
+        assert_eq!(mime_type(&normalized), Some("image/png"));
+        let image = image::load_from_memory(&normalized).unwrap();
+        assert_eq!((image.width(), image.height()), (200, 50));
+    }
+
+    #[test]
+    fn scales_images_down_and_reencodes_them() {
+        let bmp = encode(
+            DynamicImage::ImageRgb8(RgbImage::from_pixel(400, 100, Rgb([200, 10, 10]))),
+            ImageFormat::Bmp,
+        );
+
+        let normalized = normalize(&bmp, 200).unwrap();
+        assert_eq!(mime_type(&normalized), Some("image/jpeg"));
+        let image = image::load_from_memory(&normalized).unwrap();
+        assert_eq!((image.width(), image.height()), (200, 50));
+    }
+
+    #[test]
+    fn keeps_transparency_and_small_sizes() {
+        let png = encode(
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(30, 20, Rgba([0, 0, 0, 0]))),
+            ImageFormat::Png,
+        );
+
+        let normalized = normalize(&png, 200).unwrap();
+        assert_eq!(mime_type(&normalized), Some("image/png"));
+        let image = image::load_from_memory(&normalized).unwrap();
+        assert_eq!((image.width(), image.height()), (30, 20));
+    }
+
+    #[test]
+    fn refuses_images_too_large_to_decode() {
+        let bmp = encode(
+            DynamicImage::ImageRgb8(RgbImage::new(400, 100)),
+            ImageFormat::Bmp,
+        );
+
+        assert_eq!(normalize(&bmp, 200).map(|_| ()), Ok(()));
+        assert_eq!(normalize(&bmp, 50), Err(ImageError::TooLarge));
+    }
+
+    #[test]
+    fn refuses_other_content() {
+        assert_eq!(
+            normalize(b"\x89PNG\r\n\x1a\nbut not really", 200),
+            Err(ImageError::Invalid)
+        );
+        assert_eq!(
+            normalize(b"<svg></svg>", 200),
+            Err(ImageError::UnsupportedFormat)
+        );
+    }
+}
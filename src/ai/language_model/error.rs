@@ -1,27 +1,146 @@
-use std::{error::Error, fmt::Display};
+use std::{error::Error, fmt::Display, time::Duration};
 
 #[derive(Debug)]
 pub enum AILanguageModelError {
     SystemPromptError(&'static str),
     PromptInputError(&'static str),
-    ProviderError(String),
+    UnsupportedInputError(&'static str),
+    /// The create options break the rules of the Prompt API.
+    CreateOptionsError(&'static str),
+    /// The provider is receiving too many requests.
+    RateLimitedError(UpstreamError),
+    /// The provider's quota for the project is used up.
+    QuotaExceededError(UpstreamError),
+    /// The prompt or the response was blocked by the provider's safety filters.
+    SafetyBlockedError(UpstreamError),
+    /// The provider rejected the request as invalid.
+    InvalidArgumentError(UpstreamError),
+    /// The provider did not answer in time.
+    TimeoutError(UpstreamError),
+    /// The provider rejected the server's credentials.
+    AuthenticationError(UpstreamError),
+    /// The provider is temporarily unavailable.
+    UnavailableError(UpstreamError),
+    /// The request was refused without calling the provider, to protect it or the server. It may
+    /// be repeated after the error's `retry_after`.
+    OverloadedError(UpstreamError),
+    /// The client has too many requests waiting for the provider. It may be repeated after the
+    /// error's `retry_after`.
+    TooManyRequestsError(UpstreamError),
+    /// The provider failed in a way the other variants don't describe.
+    ProviderError(UpstreamError),
 }
 
 pub type AILanguageModelResult<T> = Result<T, AILanguageModelError>;
 
-impl std::error::Error for AILanguageModelError {}
+/// A failure reported by a model provider, keeping the provider's own error as its source.
+#[derive(Debug)]
+pub struct UpstreamError {
+    pub message: String,
+    /// The HTTP status of the provider's response, if it sent one.
+    pub status: Option<u16>,
+    pub source: Option<Box<dyn Error + Send + Sync>>,
+    /// How long to wait before repeating the request, if known.
+    pub retry_after: Option<Duration>,
+}
+
+impl UpstreamError {
+    pub fn new(message: impl Into<String>) -> Self {
+        UpstreamError {
+            message: message.into(),
+            status: None,
+            source: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn with_source(mut self, source: impl Error + Send + Sync + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+}
+
+impl AILanguageModelError {
+    /// Whether repeating the same request right away may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AILanguageModelError::SystemPromptError(_)
+            | AILanguageModelError::PromptInputError(_)
+            | AILanguageModelError::UnsupportedInputError(_)
+            | AILanguageModelError::CreateOptionsError(_)
+            | AILanguageModelError::QuotaExceededError(_)
+            | AILanguageModelError::SafetyBlockedError(_)
+            | AILanguageModelError::InvalidArgumentError(_)
+            | AILanguageModelError::AuthenticationError(_)
+            | AILanguageModelError::OverloadedError(_)
+            | AILanguageModelError::TooManyRequestsError(_) => false,
+            AILanguageModelError::RateLimitedError(_)
+            | AILanguageModelError::TimeoutError(_)
+            | AILanguageModelError::UnavailableError(_)
+            | AILanguageModelError::ProviderError(_) => true,
+        }
+    }
+
+    /// The upstream failure behind this error, if it came from the provider.
+    pub fn upstream(&self) -> Option<&UpstreamError> {
+        match self {
+            AILanguageModelError::RateLimitedError(err)
+            | AILanguageModelError::QuotaExceededError(err)
+            | AILanguageModelError::SafetyBlockedError(err)
+            | AILanguageModelError::InvalidArgumentError(err)
+            | AILanguageModelError::TimeoutError(err)
+            | AILanguageModelError::AuthenticationError(err)
+            | AILanguageModelError::UnavailableError(err)
+            | AILanguageModelError::OverloadedError(err)
+            | AILanguageModelError::TooManyRequestsError(err)
+            | AILanguageModelError::ProviderError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for AILanguageModelError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.upstream()?
+            .source
+            .as_deref()
+            .map(|source| source as &(dyn Error + 'static))
+    }
+}
+
 impl Display for AILanguageModelError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             AILanguageModelError::SystemPromptError(msg)
-            | AILanguageModelError::PromptInputError(msg) => write!(f, "{}", msg),
-            AILanguageModelError::ProviderError(msg) => write!(f, "{}", msg),
+            | AILanguageModelError::PromptInputError(msg)
+            | AILanguageModelError::UnsupportedInputError(msg)
+            | AILanguageModelError::CreateOptionsError(msg) => write!(f, "{}", msg),
+            AILanguageModelError::RateLimitedError(err)
+            | AILanguageModelError::QuotaExceededError(err)
+            | AILanguageModelError::SafetyBlockedError(err)
+            | AILanguageModelError::InvalidArgumentError(err)
+            | AILanguageModelError::TimeoutError(err)
+            | AILanguageModelError::AuthenticationError(err)
+            | AILanguageModelError::UnavailableError(err)
+            | AILanguageModelError::OverloadedError(err)
+            | AILanguageModelError::TooManyRequestsError(err)
+            | AILanguageModelError::ProviderError(err) => write!(f, "{}", err.message),
         }
     }
 }
 
 impl From<Box<dyn Error>> for AILanguageModelError {
     fn from(err: Box<dyn Error>) -> Self {
-        AILanguageModelError::ProviderError(err.to_string())
+        AILanguageModelError::ProviderError(UpstreamError::new(err.to_string()))
     }
 }
@@ -1,26 +1,39 @@
 mod error;
 pub mod providers;
+mod retry;
 mod types;
 
 pub use error::AILanguageModelError;
 use error::AILanguageModelResult;
+pub use error::UpstreamError;
+pub use retry::{RetryPolicy, deadline_exceeded, with_deadline};
 use tokio_stream::Stream;
 use types::AILanguageModelCapabilities;
 pub use types::AILanguageModelCreateOptions;
+pub use types::AILanguageModelGuardrails;
+pub use types::AILanguageModelHarmCategory;
+pub use types::AILanguageModelModality;
 pub use types::AILanguageModelPrompt;
 pub use types::AILanguageModelPromptRole;
-use types::AILanguageModelResponsChunk;
+pub use types::AILanguageModelResponsChunk;
+pub use types::AILanguageModelResponse;
+pub use types::AILanguageModelSafetyRating;
+pub use types::AILanguageModelSafetySettings;
+pub use types::AILanguageModelSafetyThreshold;
+pub use types::AILanguageModelUsage;
 
 pub trait AILanguageModel {
     fn create_options(&mut self, options: AILanguageModelCreateOptions);
     fn capabilities() -> &'static AILanguageModelCapabilities;
+    fn provider_id() -> &'static str;
+    fn model_id() -> &'static str;
 }
 
 pub trait Prompt: AILanguageModel {
     fn prompt(
         &self,
         inputs: &[AILanguageModelPrompt],
-    ) -> impl Future<Output = AILanguageModelResult<String>>;
+    ) -> impl Future<Output = AILanguageModelResult<AILanguageModelResponse>>;
 }
 
 pub trait PromptTreaming: AILanguageModel {
@@ -1,20 +1,32 @@
 use std::sync::Arc;
 
+use base64::{Engine, engine::general_purpose::STANDARD};
 use gcp_auth::TokenProvider;
-use gemini_rs::prelude::{Content, GeminiClient, GenerateContentRequest, GenerationConfig, Role};
+use gemini_rs::prelude::{
+    Candidate, Content, GeminiClient, GenerateContentRequest, GenerateContentResponse,
+    GenerationConfig, Role, SafetyRating, SafetySetting, UsageMetadata,
+};
 use tokio_stream::{Stream, StreamExt};
 
 use crate::ai::{
+    image,
     language_model::{
         AILanguageModel, AILanguageModelCreateOptions, AILanguageModelError, AILanguageModelPrompt,
-        AILanguageModelPromptRole, CountTokens, Prompt, PromptTreaming,
+        AILanguageModelPromptRole, CountTokens, Prompt, PromptTreaming, UpstreamError,
         error::AILanguageModelResult,
-        types::{AILanguageModelCapabilities, AILanguageModelResponsChunk},
+        types::{
+            AILanguageModelCapabilities, AILanguageModelResponsChunk, AILanguageModelResponse,
+            AILanguageModelSafetyRating, AILanguageModelUsage, assistant_prefix,
+        },
     },
     tokenizer,
 };
 
 const GEMINI_MODEL: &str = "gemini-2.0-flash-lite-001";
+// Gemini counts an image as 258 tokens for each 768 pixel tile. Images are estimated at a single
+// tile, as their size isn't known before they are decoded, and requests are charged for the
+// tokens Gemini reports once they complete.
+const IMAGE_TOKENS: usize = 258;
 
 // The default capabilities for the Gemini 2.0 Flash Lite model.
 const CAPABILITIES: AILanguageModelCapabilities = AILanguageModelCapabilities {
@@ -24,6 +36,9 @@ const CAPABILITIES: AILanguageModelCapabilities = AILanguageModelCapabilities {
     max_temperature: 1.0,
     max_top_k: 40,
     max_tokens: 1_048_576,
+    max_output_tokens: 8192,
+    max_stop_sequences: 5,
+    max_image_dimension: 3072,
 };
 
 pub struct GeminiProvider {
@@ -50,17 +65,72 @@ impl GeminiProvider {
         self.create_options.initial_prompts.iter().chain(inputs)
     }
 
+    // The assistant prefix the response continues from, if any.
+    fn prefix<'a>(
+        &'a self,
+        inputs: &'a [AILanguageModelPrompt],
+    ) -> AILanguageModelResult<Option<&'a str>> {
+        assistant_prefix(self.all_inputs(inputs))
+    }
+
     fn build_gemini_request(
         &self,
         inputs: &[AILanguageModelPrompt],
     ) -> AILanguageModelResult<GenerateContentRequest> {
-        let generation_config = GenerationConfig::builder()
-            .temperature(self.create_options.temperature)
-            .top_k(self.create_options.top_k as i32)
-            .build();
+        let guardrails = &self.create_options.guardrails;
+        let mut generation_config = GenerationConfig::builder()
+            .temperature(
+                guardrails.temperature(
+                    self.create_options
+                        .temperature
+                        .unwrap_or(CAPABILITIES.default_temperature),
+                ),
+            )
+            .top_k(
+                guardrails.top_k(
+                    self.create_options
+                        .top_k
+                        .unwrap_or(CAPABILITIES.default_top_k),
+                ) as i32,
+            )
+            .top_p(
+                self.create_options
+                    .top_p
+                    .unwrap_or(CAPABILITIES.default_top_p),
+            );
+        let options = &self.create_options;
+        if let Some(max_output_tokens) = guardrails.output_tokens(options.max_output_tokens) {
+            generation_config = generation_config.max_output_tokens(max_output_tokens as i32);
+        }
+        if !options.stop_sequences.is_empty() {
+            generation_config = generation_config.stop_sequences(options.stop_sequences.clone());
+        }
+        if let Some(seed) = options.seed {
+            generation_config = generation_config.seed(seed);
+        }
+        if let Some(presence_penalty) = options.presence_penalty {
+            generation_config = generation_config.presence_penalty(presence_penalty);
+        }
+        if let Some(frequency_penalty) = options.frequency_penalty {
+            generation_config = generation_config.frequency_penalty(frequency_penalty);
+        }
+        let generation_config = generation_config.build();
 
         let mut request_builder =
             GenerateContentRequest::builder().generation_config(generation_config);
+        if !guardrails.safety_settings.0.is_empty() {
+            request_builder = request_builder.safety_settings(
+                guardrails
+                    .safety_settings
+                    .0
+                    .iter()
+                    .map(|(category, threshold)| SafetySetting {
+                        category: category.as_str().to_string(),
+                        threshold: threshold.as_str().to_string(),
+                    })
+                    .collect(),
+            );
+        }
 
         // Set the System Prompt.
         if let Some(system_prompt) = self.create_options.system_prompt_text()? {
@@ -68,12 +138,19 @@ impl GeminiProvider {
                 .system_instruction(Content::builder().add_text_part(system_prompt).build());
         }
 
-        // Set the User / Assistant Prompts.
+        // Set the User / Assistant Prompts. A trailing assistant prefix is sent as the last model
+        // turn, which Gemini continues from, so it only needs validating here.
+        self.prefix(inputs)?;
         let mut contents: Vec<Content> = vec![];
 
         for input in self.all_inputs(inputs) {
+            if !guardrails.allows(input) {
+                return Err(AILanguageModelError::UnsupportedInputError(
+                    "The input type is not allowed.",
+                ));
+            }
             match input {
-                AILanguageModelPrompt::Text { role, content } => {
+                AILanguageModelPrompt::Text { role, content, .. } => {
                     let role = match role {
                         AILanguageModelPromptRole::User => Role::User,
                         AILanguageModelPromptRole::Assistant => Role::Model,
@@ -82,8 +159,25 @@ impl GeminiProvider {
 
                     contents.push(Content::builder().role(role).add_text_part(content).build());
                 }
+                AILanguageModelPrompt::Image { role, content } => {
+                    let role = match role {
+                        AILanguageModelPromptRole::User => Role::User,
+                        AILanguageModelPromptRole::Assistant => Role::Model,
+                        _ => continue,
+                    };
+                    let mime_type = image::mime_type(content).ok_or(
+                        AILanguageModelError::UnsupportedInputError("Unsupported image format"),
+                    )?;
+
+                    contents.push(
+                        Content::builder()
+                            .role(role)
+                            .add_inline_data_part(mime_type, STANDARD.encode(content))
+                            .build(),
+                    );
+                }
                 _ => {
-                    return Err(AILanguageModelError::PromptInputError(
+                    return Err(AILanguageModelError::UnsupportedInputError(
                         "Unsupported input type",
                     ));
                 }
@@ -103,26 +197,52 @@ impl AILanguageModel for GeminiProvider {
     fn capabilities() -> &'static AILanguageModelCapabilities {
         &CAPABILITIES
     }
+
+    fn provider_id() -> &'static str {
+        "gemini"
+    }
+
+    fn model_id() -> &'static str {
+        GEMINI_MODEL
+    }
 }
 
 impl Prompt for GeminiProvider {
-    async fn prompt(&self, inputs: &[AILanguageModelPrompt]) -> AILanguageModelResult<String> {
+    async fn prompt(
+        &self,
+        inputs: &[AILanguageModelPrompt],
+    ) -> AILanguageModelResult<AILanguageModelResponse> {
         let gemini_request = self.build_gemini_request(inputs)?;
+        let mut prefix_filter = PrefixEchoFilter::new(self.prefix(inputs)?);
         let gemini_response = self
             .gemini_client
             .generate_content(&gemini_request, GEMINI_MODEL)
             .await
-            .map_err(|e| AILanguageModelError::ProviderError(e.to_string()))?;
+            .map_err(classify_error)?;
 
-        let text = gemini_response
-            .candidates
-            .first()
-            .ok_or(gemini_rs::error::Error::NoCandidatesError)
-            .map_err(|e| AILanguageModelError::ProviderError(e.to_string()))?
-            .get_text()
-            .unwrap_or_default();
+        let Some(candidate) = gemini_response.candidates.first() else {
+            return Err(blocked_prompt_error(&gemini_response)
+                .unwrap_or_else(|| classify_error(gemini_rs::error::Error::NoCandidatesError)));
+        };
 
-        Ok(text)
+        let text = candidate.get_text().unwrap_or_default();
+        let text = join_text(prefix_filter.push(&text), prefix_filter.finish()).unwrap_or_default();
+
+        Ok(AILanguageModelResponse {
+            text,
+            finish_reason: candidate.finish_reason.clone(),
+            block_reason: block_reason(candidate),
+            usage: gemini_response
+                .usage_metadata
+                .as_ref()
+                .map(usage_from_metadata),
+            safety_ratings: safety_ratings(candidate),
+            model_id: gemini_response
+                .model_version
+                .clone()
+                .unwrap_or_else(|| GEMINI_MODEL.to_string()),
+            response_id: gemini_response.response_id.clone(),
+        })
     }
 }
 
@@ -133,14 +253,15 @@ impl PromptTreaming for GeminiProvider {
     ) -> AILanguageModelResult<impl Stream<Item = AILanguageModelResult<AILanguageModelResponsChunk>>>
     {
         let gemini_request = self.build_gemini_request(inputs)?;
+        let mut prefix_filter = PrefixEchoFilter::new(self.prefix(inputs)?);
         let stream = self
             .gemini_client
             .generate_content_stream(&gemini_request, GEMINI_MODEL)
             .await
-            .map_err(|e| AILanguageModelError::ProviderError(e.to_string()))?;
+            .map_err(classify_error)?;
 
         // Transform a Gemini stream into a Stream of AILanguageModelResult<String>.
-        let stream = stream.filter_map(|response| {
+        let stream = stream.filter_map(move |response| {
             let response = match response {
                 Ok(response) => response,
 
@@ -148,24 +269,46 @@ impl PromptTreaming for GeminiProvider {
                 // returning an error, we return a finished chunk without data.
                 Err(gemini_rs::error::Error::EventSourceClosedError) => {
                     return Some(Ok(AILanguageModelResponsChunk {
-                        text: None,
+                        text: prefix_filter.finish(),
                         finished: true,
+                        ..Default::default()
                     }));
                 }
-                Err(e) => {
-                    return Some(Err(AILanguageModelError::ProviderError(e.to_string())));
-                }
+                Err(e) => return Some(Err(classify_error(e))),
             };
 
+            // Usage metadata may arrive on a chunk of its own, after the last candidate.
+            let usage = response.usage_metadata.as_ref().map(usage_from_metadata);
+
             // TODO: A chunk without candidates is weird, maybe return an error here.
             let Some(candidate) = response.candidates.first() else {
-                return None;
+                if let Some(error) = blocked_prompt_error(&response) {
+                    return Some(Err(error));
+                }
+                return usage.map(|usage| {
+                    Ok(AILanguageModelResponsChunk {
+                        usage: Some(usage),
+                        ..Default::default()
+                    })
+                });
             };
 
             let finished = candidate.finish_reason.is_some();
-            let text = candidate.get_text();
+            let mut text = candidate
+                .get_text()
+                .and_then(|text| prefix_filter.push(&text));
+            if finished {
+                text = join_text(text, prefix_filter.finish());
+            }
 
-            Some(Ok(AILanguageModelResponsChunk { text, finished }))
+            Some(Ok(AILanguageModelResponsChunk {
+                text,
+                finished,
+                finish_reason: candidate.finish_reason.clone(),
+                block_reason: block_reason(candidate),
+                usage,
+                safety_ratings: safety_ratings(candidate),
+            }))
         });
         Ok(stream)
     }
@@ -173,16 +316,202 @@ impl PromptTreaming for GeminiProvider {
 
 impl CountTokens for GeminiProvider {
     fn count_tokens(&self, inputs: &[AILanguageModelPrompt]) -> AILanguageModelResult<usize> {
+        self.prefix(inputs)?;
         let all_inputs = self.all_inputs(inputs);
         let prompt = build_gemma_prompt(&self.create_options, all_inputs)?;
-        let total_tokens = tokenizer::count_tokens(&prompt)
-            .map_err(|e| AILanguageModelError::ProviderError(e.to_string()))?;
-        Ok(total_tokens)
+        let text_tokens = tokenizer::count_tokens(&prompt)
+            .map_err(|e| AILanguageModelError::ProviderError(UpstreamError::new(e.to_string())))?;
+        Ok(text_tokens + image_tokens(self.all_inputs(inputs)))
+    }
+}
+
+// Gemini sometimes repeats the assistant prefix at the start of its continuation. This strips the
+// echo, so both `prompt` and `prompt_streaming` return only the text that follows the prefix.
+struct PrefixEchoFilter {
+    prefix: Option<String>,
+    pending: String,
+}
+
+impl PrefixEchoFilter {
+    fn new(prefix: Option<&str>) -> Self {
+        PrefixEchoFilter {
+            prefix: prefix
+                .filter(|prefix| !prefix.is_empty())
+                .map(str::to_string),
+            pending: String::new(),
+        }
+    }
+
+    // Returns the text that can be emitted so far. Text is held back while it could still be the
+    // start of an echoed prefix.
+    fn push(&mut self, text: &str) -> Option<String> {
+        let Some(prefix) = &self.prefix else {
+            return Some(text.to_string()).filter(|text| !text.is_empty());
+        };
+
+        self.pending.push_str(text);
+        if self.pending.len() < prefix.len() && prefix.starts_with(&self.pending) {
+            return None;
+        }
+
+        let pending = std::mem::take(&mut self.pending);
+        let text = pending.strip_prefix(prefix.as_str()).unwrap_or(&pending);
+        let text = Some(text.to_string()).filter(|text| !text.is_empty());
+        self.prefix = None;
+        text
+    }
+
+    // Flushes any text held back when the response ends.
+    fn finish(&mut self) -> Option<String> {
+        self.prefix = None;
+        Some(std::mem::take(&mut self.pending)).filter(|text| !text.is_empty())
+    }
+}
+
+// Sorts a Gemini failure into the error taxonomy, keeping the original error as the source.
+fn classify_error(err: gemini_rs::error::Error) -> AILanguageModelError {
+    use gemini_rs::error::Error;
+
+    let (classification, message, status): (Classification, String, Option<u16>) = match &err {
+        Error::VertexError(vertex) => {
+            let status = u16::try_from(vertex.code).ok();
+            let classification = classify_status(status, &vertex.status, &vertex.message);
+            (classification, vertex.message.clone(), status)
+        }
+        Error::HttpClient(e) if e.is_timeout() => {
+            (AILanguageModelError::TimeoutError, e.to_string(), None)
+        }
+        Error::HttpClient(e) => match e.status() {
+            Some(status) => {
+                let status = status.as_u16();
+                let classification = classify_status(Some(status), "", "");
+                (classification, e.to_string(), Some(status))
+            }
+            None if e.is_connect() || e.is_request() || e.is_body() => {
+                (AILanguageModelError::UnavailableError, e.to_string(), None)
+            }
+            None => (unclassified, e.to_string(), None),
+        },
+        Error::EventSourceError(_) => (
+            AILanguageModelError::UnavailableError,
+            err.to_string(),
+            None,
+        ),
+        _ => (unclassified, err.to_string(), None),
+    };
+
+    let mut upstream = UpstreamError::new(message).with_source(err);
+    upstream.status = status;
+    classification(upstream)
+}
+
+type Classification = fn(UpstreamError) -> AILanguageModelError;
+
+fn unclassified(err: UpstreamError) -> AILanguageModelError {
+    AILanguageModelError::ProviderError(err)
+}
+
+// Classifies an error response from its HTTP status and, for Vertex AI errors, its canonical
+// status name. See https://cloud.google.com/vertex-ai/generative-ai/docs/error-code-reference
+fn classify_status(code: Option<u16>, status: &str, message: &str) -> Classification {
+    match (code, status) {
+        (_, "RESOURCE_EXHAUSTED") | (Some(429), _) => {
+            // Vertex AI reports both per minute rate limits and exhausted daily or billing quotas
+            // as RESOURCE_EXHAUSTED, only the message tells them apart.
+            let message = message.to_lowercase();
+            if ["per_day", "per day", "billing"]
+                .iter()
+                .any(|needle| message.contains(needle))
+            {
+                AILanguageModelError::QuotaExceededError
+            } else {
+                AILanguageModelError::RateLimitedError
+            }
+        }
+        (_, "INVALID_ARGUMENT" | "FAILED_PRECONDITION" | "OUT_OF_RANGE") | (Some(400), _) => {
+            AILanguageModelError::InvalidArgumentError
+        }
+        (_, "UNAUTHENTICATED" | "PERMISSION_DENIED") | (Some(401 | 403), _) => {
+            AILanguageModelError::AuthenticationError
+        }
+        (_, "DEADLINE_EXCEEDED") | (Some(408 | 504), _) => AILanguageModelError::TimeoutError,
+        (_, "UNAVAILABLE" | "INTERNAL") | (Some(500 | 502 | 503), _) => {
+            AILanguageModelError::UnavailableError
+        }
+        _ => unclassified,
+    }
+}
+
+// Gemini answers a prompt blocked by its safety filters without candidates, only giving the
+// reason in the prompt feedback.
+fn blocked_prompt_error(response: &GenerateContentResponse) -> Option<AILanguageModelError> {
+    let block_reason = response.prompt_feedback.as_ref()?.block_reason.as_ref()?;
+    Some(AILanguageModelError::SafetyBlockedError(
+        UpstreamError::new(format!(
+            "The prompt was blocked by the safety filters ({}).",
+            block_reason
+        )),
+    ))
+}
+
+fn usage_from_metadata(metadata: &UsageMetadata) -> AILanguageModelUsage {
+    AILanguageModelUsage {
+        input_tokens: metadata.prompt_token_count.unwrap_or_default(),
+        output_tokens: metadata.candidates_token_count.unwrap_or_default(),
+        total_tokens: metadata.total_token_count.unwrap_or_default(),
+    }
+}
+
+fn safety_rating(rating: &SafetyRating) -> AILanguageModelSafetyRating {
+    AILanguageModelSafetyRating {
+        category: rating.category.clone(),
+        probability: rating.probability.clone(),
+        blocked: rating.blocked.unwrap_or_default(),
+    }
+}
+
+fn safety_ratings(candidate: &Candidate) -> Vec<AILanguageModelSafetyRating> {
+    candidate
+        .safety_ratings
+        .iter()
+        .flatten()
+        .map(safety_rating)
+        .collect()
+}
+
+// The finish reasons of a candidate stopped by the safety filters, rather than by the model.
+const BLOCKED_FINISH_REASONS: [&str; 5] = [
+    "SAFETY",
+    "PROHIBITED_CONTENT",
+    "BLOCKLIST",
+    "SPII",
+    "IMAGE_SAFETY",
+];
+
+fn block_reason(candidate: &Candidate) -> Option<String> {
+    candidate
+        .finish_reason
+        .clone()
+        .filter(|reason| BLOCKED_FINISH_REASONS.contains(&reason.as_str()))
+}
+
+fn join_text(first: Option<String>, second: Option<String>) -> Option<String> {
+    match (first, second) {
+        (Some(first), Some(second)) => Some(first + &second),
+        (first, second) => first.or(second),
     }
 }
 
 // Formats the prompt according to theh Gemma requirements.
 // See https://ai.google.dev/gemma/docs/core/prompt-structure
+// The estimated tokens of the images among `inputs`, which the text prompt leaves out.
+fn image_tokens<'a>(inputs: impl Iterator<Item = &'a AILanguageModelPrompt>) -> usize {
+    inputs
+        .filter(|input| matches!(input, AILanguageModelPrompt::Image { .. }))
+        .count()
+        * IMAGE_TOKENS
+}
+
 fn build_gemma_prompt<'a>(
     create_options: &AILanguageModelCreateOptions,
     inputs: impl Iterator<Item = &'a AILanguageModelPrompt>,
@@ -197,7 +526,7 @@ fn build_gemma_prompt<'a>(
 
     for input in inputs {
         let (user_or_model, content) = match input {
-            AILanguageModelPrompt::Text { role, content } => match role {
+            AILanguageModelPrompt::Text { role, content, .. } => match role {
                 AILanguageModelPromptRole::User => (USER, content),
                 AILanguageModelPromptRole::Assistant => (MODEL, content),
                 _ => continue,
@@ -206,15 +535,140 @@ fn build_gemma_prompt<'a>(
         };
         prompt.push_str(START_OF_TURN);
         prompt.push_str(user_or_model);
-        prompt.push_str("\n");
+        prompt.push('\n');
         if let Some(system) = system_prompt.take() {
             prompt.push_str(&system);
             prompt.push_str("\n\n");
             system_prompt = None;
         }
-        prompt.push_str(&content);
+        prompt.push_str(content);
+
+        // A prefix leaves the model turn open, as the model continues writing it.
+        if input.is_prefix() {
+            break;
+        }
         prompt.push_str(END_OF_TURN);
-        prompt.push_str("\n");
+        prompt.push('\n');
     }
     Ok(prompt)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+
+    fn text(role: AILanguageModelPromptRole, content: &str, prefix: bool) -> AILanguageModelPrompt {
+        AILanguageModelPrompt::Text {
+            role,
+            content: content.to_string(),
+            prefix,
+        }
+    }
+
+    #[test]
+    fn gemma_prompt_leaves_prefix_turn_open() {
+        let inputs = [
+            text(AILanguageModelPromptRole::User, "List three colors.", false),
+            text(AILanguageModelPromptRole::Assistant, "1.", true),
+        ];
+
+        let prompt =
+            build_gemma_prompt(&AILanguageModelCreateOptions::default(), inputs.iter()).unwrap();
+
+        assert_eq!(
+            prompt,
+            "<start_of_turn>user\nList three colors.<end_of_turn>\n<start_of_turn>model\n1."
+        );
+    }
+
+    #[test]
+    fn estimates_the_tokens_of_images() {
+        let image = || AILanguageModelPrompt::Image {
+            role: AILanguageModelPromptRole::User,
+            content: vec![],
+        };
+        let inputs = [
+            image(),
+            text(AILanguageModelPromptRole::User, "Which is larger?", false),
+            image(),
+        ];
+
+        assert_eq!(image_tokens(inputs.iter()), 2 * IMAGE_TOKENS);
+    }
+
+    #[test]
+    fn prefix_filter_strips_echo_split_across_chunks() {
+        let mut filter = PrefixEchoFilter::new(Some("```json"));
+
+        assert_eq!(filter.push("```"), None);
+        assert_eq!(filter.push("json\n{"), Some("\n{".to_string()));
+        assert_eq!(filter.push("}"), Some("}".to_string()));
+        assert_eq!(filter.finish(), None);
+    }
+
+    #[test]
+    fn prefix_filter_keeps_text_without_echo() {
+        let mut filter = PrefixEchoFilter::new(Some("```json"));
+
+        assert_eq!(filter.push("\n{}"), Some("\n{}".to_string()));
+    }
+
+    #[test]
+    fn prefix_filter_flushes_partial_match_on_finish() {
+        let mut filter = PrefixEchoFilter::new(Some("```json"));
+
+        assert_eq!(filter.push("``"), None);
+        assert_eq!(filter.finish(), Some("``".to_string()));
+    }
+
+    #[test]
+    fn classifies_vertex_errors() {
+        let classify = |code, status, message| {
+            classify_status(Some(code), status, message)(UpstreamError::new(message))
+        };
+
+        assert!(matches!(
+            classify(
+                429,
+                "RESOURCE_EXHAUSTED",
+                "Resource exhausted. Please try again later."
+            ),
+            AILanguageModelError::RateLimitedError(_)
+        ));
+        assert!(matches!(
+            classify(
+                429,
+                "RESOURCE_EXHAUSTED",
+                "Quota exceeded for requests_per_day."
+            ),
+            AILanguageModelError::QuotaExceededError(_)
+        ));
+        assert!(matches!(
+            classify(400, "INVALID_ARGUMENT", "Bad request"),
+            AILanguageModelError::InvalidArgumentError(_)
+        ));
+        assert!(matches!(
+            classify(403, "PERMISSION_DENIED", "Denied"),
+            AILanguageModelError::AuthenticationError(_)
+        ));
+        assert!(matches!(
+            classify(503, "UNAVAILABLE", "Overloaded"),
+            AILanguageModelError::UnavailableError(_)
+        ));
+        assert!(matches!(
+            classify(504, "DEADLINE_EXCEEDED", "Too slow"),
+            AILanguageModelError::TimeoutError(_)
+        ));
+        assert!(matches!(
+            classify(418, "", "Teapot"),
+            AILanguageModelError::ProviderError(_)
+        ));
+
+        let unclassified = unclassified(
+            UpstreamError::new("Teapot").with_source(std::io::Error::other("Brewing")),
+        );
+        assert_eq!(unclassified.source().unwrap().to_string(), "Brewing");
+    }
+}
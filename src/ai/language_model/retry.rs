@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::time::Instant;
+use tracing::warn;
+
+use super::{AILanguageModelError, UpstreamError, error::AILanguageModelResult};
+
+/// Retries provider calls that failed with a retryable error, waiting an exponentially growing,
+/// jittered delay between attempts.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// How many times a call is attempted, including the first attempt.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(4),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before the next attempt, after `failed_attempts` attempts failed. Returns `None`
+    /// when no attempts are left.
+    ///
+    /// The delay is drawn between half and all of the exponential backoff, so clients that failed
+    /// together don't all retry at the same time.
+    pub fn backoff(&self, failed_attempts: u32) -> Option<Duration> {
+        if failed_attempts == 0 || failed_attempts >= self.max_attempts {
+            return None;
+        }
+
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(failed_attempts - 1))
+            .min(self.max_backoff);
+        Some(rand::rng().random_range(backoff / 2..=backoff))
+    }
+
+    /// Runs `operation` until it succeeds, fails with an error that isn't retryable, or runs out
+    /// of attempts.
+    pub async fn retry<T, F, Fut>(&self, mut operation: F) -> AILanguageModelResult<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = AILanguageModelResult<T>>,
+    {
+        let mut failed_attempts = 0;
+        loop {
+            let error = match operation().await {
+                Err(error) if error.is_retryable() => error,
+                result => return result,
+            };
+
+            failed_attempts += 1;
+            let Some(backoff) = self.backoff(failed_attempts) else {
+                return Err(error);
+            };
+            warn!(attempt = failed_attempts, backoff = ?backoff, "Retrying provider call: {}", error);
+            tokio::time::sleep(backoff).await;
+        }
+    }
+}
+
+/// Fails `future` with a [`AILanguageModelError::TimeoutError`] if it does not complete before
+/// `deadline`.
+pub async fn with_deadline<T>(
+    deadline: Instant,
+    future: impl Future<Output = AILanguageModelResult<T>>,
+) -> AILanguageModelResult<T> {
+    tokio::time::timeout_at(deadline, future)
+        .await
+        .unwrap_or_else(|_| Err(deadline_exceeded()))
+}
+
+pub fn deadline_exceeded() -> AILanguageModelError {
+    AILanguageModelError::TimeoutError(UpstreamError::new("The request deadline was exceeded."))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        max_attempts: 3,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(2),
+    };
+
+    #[test]
+    fn backoff_grows_up_to_the_maximum() {
+        let policy = RetryPolicy::default();
+
+        let first = policy.backoff(1).unwrap();
+        assert!(first >= Duration::from_millis(125) && first <= Duration::from_millis(250));
+
+        let second = policy.backoff(2).unwrap();
+        assert!(second >= Duration::from_millis(250) && second <= Duration::from_millis(500));
+
+        assert_eq!(policy.backoff(3), None);
+    }
+
+    #[tokio::test]
+    async fn retries_retryable_errors() {
+        let attempts = AtomicU32::new(0);
+
+        let result = POLICY
+            .retry(|| async {
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Err(AILanguageModelError::UnavailableError(UpstreamError::new(
+                        "Unavailable",
+                    )))
+                } else {
+                    Ok("Hello")
+                }
+            })
+            .await;
+
+        assert_eq!(result.unwrap(), "Hello");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_other_errors() {
+        let attempts = AtomicU32::new(0);
+
+        let result: AILanguageModelResult<()> = POLICY
+            .retry(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(AILanguageModelError::PromptInputError("Invalid"))
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let attempts = AtomicU32::new(0);
+
+        let result: AILanguageModelResult<()> = POLICY
+            .retry(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(AILanguageModelError::RateLimitedError(UpstreamError::new(
+                    "Slow down",
+                )))
+            })
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(AILanguageModelError::RateLimitedError(_))
+        ));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
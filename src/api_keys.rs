@@ -0,0 +1,388 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use rusqlite::{Connection, Row, params, types::Type};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{ledger::unix_now, limits::Quota};
+use built_in_hybrid_server::ai::language_model::AILanguageModelGuardrails;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS api_keys (
+        id TEXT PRIMARY KEY,
+        hash TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        endpoints TEXT NOT NULL,
+        models TEXT NOT NULL,
+        limits TEXT,
+        guardrails TEXT,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER,
+        revoked_at INTEGER
+    );
+";
+
+// Makes keys recognizable, for secret scanners and for people.
+const SECRET_PREFIX: &str = "bih_";
+
+/// A key that lets server-to-server clients call the API without a browser `Origin`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// The endpoints the key may call, as paths below `/language-model`, each allowing the paths
+    /// below it. Empty allows every endpoint.
+    pub endpoints: Vec<String>,
+    /// The models the key may use. Empty allows every model.
+    pub models: Vec<String>,
+    /// The limits of the key, replacing the default API key limits.
+    pub limits: Option<Quota>,
+    /// The guardrails of the requests made with the key, or with the client tokens it issued.
+    pub guardrails: AILanguageModelGuardrails,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+}
+
+impl ApiKey {
+    pub fn allows_endpoint(&self, path: &str) -> bool {
+        allows_endpoint(&self.endpoints, path)
+    }
+
+    pub fn allows_model(&self, model: &str) -> bool {
+        allows_model(&self.models, model)
+    }
+
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// The settings of a key to create.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct NewApiKey {
+    pub name: String,
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    #[serde(default)]
+    pub models: Vec<String>,
+    pub limits: Option<Quota>,
+    #[serde(default)]
+    pub guardrails: AILanguageModelGuardrails,
+    /// When the key stops working, in seconds since the Unix epoch.
+    pub expires_at: Option<i64>,
+}
+
+/// The API keys, stored in SQLite. Only a SHA-256 hash of each key's secret is stored, so the
+/// secret is only known when the key is created or rotated.
+#[derive(Debug)]
+pub struct ApiKeys {
+    connection: Mutex<Connection>,
+    // The keys that aren't revoked, by the hash of their secret.
+    keys: RwLock<HashMap<String, Arc<ApiKey>>>,
+}
+
+impl ApiKeys {
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        // Databases created before keys had guardrails lack their column.
+        let has_guardrails = connection
+            .prepare("SELECT 1 FROM pragma_table_info('api_keys') WHERE name = 'guardrails'")?
+            .exists([])?;
+        if !has_guardrails {
+            connection.execute("ALTER TABLE api_keys ADD COLUMN guardrails TEXT", [])?;
+        }
+
+        let keys = connection
+            .prepare(
+                "SELECT hash, id, name, endpoints, models, limits, guardrails, created_at,
+                    expires_at
+                FROM api_keys
+                WHERE revoked_at IS NULL",
+            )?
+            .query_map([], |row| {
+                let key = ApiKey {
+                    id: row.get(1)?,
+                    name: row.get(2)?,
+                    endpoints: from_json(row, 3)?.unwrap_or_default(),
+                    models: from_json(row, 4)?.unwrap_or_default(),
+                    limits: from_json(row, 5)?,
+                    // Keys created before keys had guardrails have none.
+                    guardrails: from_json(row, 6)?.unwrap_or_default(),
+                    created_at: row.get(7)?,
+                    expires_at: row.get(8)?,
+                };
+                Ok((row.get(0)?, Arc::new(key)))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(ApiKeys {
+            connection: Mutex::new(connection),
+            keys: RwLock::new(keys),
+        })
+    }
+
+    /// The key with the given secret, unless it is unknown, revoked or expired.
+    pub fn authenticate(&self, secret: &str) -> Option<Arc<ApiKey>> {
+        let key = self.keys.read().unwrap().get(&hash(secret)).cloned()?;
+        (!key.is_expired(unix_now())).then_some(key)
+    }
+
+    /// The key with the given id, unless it is unknown, revoked or expired.
+    pub fn get(&self, id: &str) -> Option<Arc<ApiKey>> {
+        let key = self
+            .keys
+            .read()
+            .unwrap()
+            .values()
+            .find(|key| key.id == id)
+            .cloned()?;
+        (!key.is_expired(unix_now())).then_some(key)
+    }
+
+    /// Whether `key`, authenticated earlier, still works: it wasn't revoked, rotated or expired
+    /// since.
+    pub fn is_current(&self, key: &Arc<ApiKey>) -> bool {
+        self.get(&key.id)
+            .is_some_and(|current| Arc::ptr_eq(&current, key))
+    }
+
+    /// The keys that aren't revoked, oldest first.
+    pub fn list(&self) -> Vec<ApiKey> {
+        let mut keys = self
+            .keys
+            .read()
+            .unwrap()
+            .values()
+            .map(|key| ApiKey::clone(key))
+            .collect::<Vec<_>>();
+        keys.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        keys
+    }
+
+    /// Creates a key, returning it with its secret.
+    pub fn create(&self, new_key: NewApiKey) -> rusqlite::Result<(ApiKey, String)> {
+        let key = ApiKey {
+            id: Uuid::new_v4().simple().to_string(),
+            name: new_key.name,
+            endpoints: new_key.endpoints,
+            models: new_key.models,
+            limits: new_key.limits,
+            guardrails: new_key.guardrails,
+            created_at: unix_now(),
+            expires_at: new_key.expires_at,
+        };
+        let secret = new_secret();
+
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO api_keys (id, hash, name, endpoints, models, limits, guardrails,
+                created_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                key.id,
+                hash(&secret),
+                key.name,
+                to_json(&key.endpoints),
+                to_json(&key.models),
+                key.limits.as_ref().map(to_json),
+                to_json(&key.guardrails),
+                key.created_at,
+                key.expires_at,
+            ],
+        )?;
+        self.keys
+            .write()
+            .unwrap()
+            .insert(hash(&secret), Arc::new(key.clone()));
+        Ok((key, secret))
+    }
+
+    /// Replaces the secret of a key, returning the key with its new secret. The old secret stops
+    /// working right away.
+    pub fn rotate(&self, id: &str) -> rusqlite::Result<Option<(ApiKey, String)>> {
+        let secret = new_secret();
+
+        let connection = self.connection.lock().unwrap();
+        let mut keys = self.keys.write().unwrap();
+        let Some(old_hash) = keys
+            .iter()
+            .find(|(_, key)| key.id == id)
+            .map(|(old_hash, _)| old_hash.clone())
+        else {
+            return Ok(None);
+        };
+        connection.execute(
+            "UPDATE api_keys SET hash = ?1 WHERE id = ?2",
+            params![hash(&secret), id],
+        )?;
+
+        // A new `Arc`, so the key authenticated with the old secret is no longer current.
+        let key = ApiKey::clone(&keys.remove(&old_hash).expect("The key was found above"));
+        keys.insert(hash(&secret), Arc::new(key.clone()));
+        Ok(Some((key, secret)))
+    }
+
+    /// Revokes a key. Returns whether the key existed and wasn't revoked yet.
+    pub fn revoke(&self, id: &str) -> rusqlite::Result<bool> {
+        let connection = self.connection.lock().unwrap();
+        let revoked = connection.execute(
+            "UPDATE api_keys SET revoked_at = ?1 WHERE id = ?2 AND revoked_at IS NULL",
+            params![unix_now(), id],
+        )?;
+        self.keys.write().unwrap().retain(|_, key| key.id != id);
+        Ok(revoked > 0)
+    }
+}
+
+/// Whether `endpoints` allow `path`, either listing it or a path above it. Empty allows every path.
+pub fn allows_endpoint(endpoints: &[String], path: &str) -> bool {
+    endpoints.is_empty()
+        || endpoints.iter().any(|endpoint| {
+            path.strip_prefix(endpoint.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+}
+
+/// Whether `models` allow `model`. Empty allows every model.
+pub fn allows_model(models: &[String], model: &str) -> bool {
+    models.is_empty() || models.iter().any(|allowed| allowed == model)
+}
+
+fn new_secret() -> String {
+    format!("{}{}", SECRET_PREFIX, to_hex(&rand::random::<[u8; 32]>()))
+}
+
+fn hash(secret: &str) -> String {
+    to_hex(&Sha256::digest(secret.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("Key settings serialize to JSON")
+}
+
+// Reads settings written by `to_json`. Settings that don't parse, written by another version,
+// fail rather than leaving the key without its restrictions.
+fn from_json<T: DeserializeOwned>(row: &Row, index: usize) -> rusqlite::Result<Option<T>> {
+    row.get::<_, Option<String>>(index)?
+        .map(|json| {
+            serde_json::from_str(&json).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(e))
+            })
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_key() -> NewApiKey {
+        NewApiKey {
+            name: "Backend".to_string(),
+            endpoints: vec!["/prompt-streaming".to_string()],
+            models: vec![],
+            limits: None,
+            guardrails: AILanguageModelGuardrails::default(),
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn refuses_unknown_settings() {
+        assert!(serde_json::from_str::<NewApiKey>(r#"{"name":"Backend","expiresIn":60}"#).is_err());
+        assert!(
+            serde_json::from_str::<NewApiKey>(
+                r#"{"name":"Backend","guardrails":{"maxTemprature":0.5}}"#
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn authenticates_keys_by_secret() {
+        let keys = ApiKeys::open(":memory:").unwrap();
+        let (key, secret) = keys.create(new_key()).unwrap();
+
+        assert!(secret.starts_with(SECRET_PREFIX));
+        assert_eq!(keys.authenticate(&secret).unwrap().id, key.id);
+        assert!(keys.authenticate("bih_unknown").is_none());
+        assert_eq!(keys.get(&key.id).unwrap().name, "Backend");
+    }
+
+    #[test]
+    fn rotating_replaces_the_secret() {
+        let keys = ApiKeys::open(":memory:").unwrap();
+        let (key, old_secret) = keys.create(new_key()).unwrap();
+        let authenticated = keys.authenticate(&old_secret).unwrap();
+        assert!(keys.is_current(&authenticated));
+
+        let (rotated, new_secret) = keys.rotate(&key.id).unwrap().unwrap();
+
+        assert_eq!(rotated, key);
+        assert!(keys.authenticate(&old_secret).is_none());
+        assert!(keys.authenticate(&new_secret).is_some());
+        assert!(!keys.is_current(&authenticated));
+    }
+
+    #[test]
+    fn refuses_revoked_and_expired_keys() {
+        let keys = ApiKeys::open(":memory:").unwrap();
+        let (key, secret) = keys.create(new_key()).unwrap();
+        let (_, expired_secret) = keys
+            .create(NewApiKey {
+                expires_at: Some(unix_now() - 1),
+                ..new_key()
+            })
+            .unwrap();
+
+        assert!(keys.revoke(&key.id).unwrap());
+        assert!(!keys.revoke(&key.id).unwrap());
+        assert!(keys.authenticate(&secret).is_none());
+        assert!(keys.get(&key.id).is_none());
+        assert!(keys.rotate(&key.id).unwrap().is_none());
+        assert!(keys.authenticate(&expired_secret).is_none());
+    }
+
+    #[test]
+    fn scopes_keys_to_endpoints() {
+        let keys = ApiKeys::open(":memory:").unwrap();
+        let (key, _) = keys.create(new_key()).unwrap();
+
+        assert!(key.allows_endpoint("/prompt-streaming"));
+        assert!(key.allows_endpoint("/prompt-streaming/resume"));
+        assert!(!key.allows_endpoint("/prompt"));
+        assert!(!key.allows_endpoint("/prompt-streaming-v2"));
+    }
+
+    #[test]
+    fn refuses_to_open_keys_whose_settings_do_not_parse() {
+        let path = std::env::temp_dir().join(format!("api-keys-{}.db", Uuid::new_v4()));
+        let keys = ApiKeys::open(&path).unwrap();
+        keys.create(new_key()).unwrap();
+        keys.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE api_keys SET guardrails = '{\"maxTemprature\":0.5}'",
+                [],
+            )
+            .unwrap();
+        drop(keys);
+
+        let opened = ApiKeys::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(opened.is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt::Display;
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    api_keys::{allows_endpoint, allows_model},
+    limits::Quota,
+};
+
+const VERSION: &str = "v1";
+
+/// A short-lived token a site's backend issues to its pages, so they can call the API from the
+/// browser. The token is bound to the site's origin, and can narrow down what the pages may do.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientToken {
+    pub id: String,
+    /// The id of the API key that issued the token.
+    pub key: String,
+    /// The origin the token may be used from.
+    pub origin: String,
+    /// The endpoints the token may call, as paths below `/language-model`. Empty allows them all.
+    pub endpoints: Vec<String>,
+    /// The models the token may use. Empty allows every model.
+    pub models: Vec<String>,
+    /// The limits of the token, on top of the limits of its origin.
+    pub limits: Option<Quota>,
+    pub issued_at: i64,
+    pub expires_at: i64,
+}
+
+impl ClientToken {
+    pub fn allows_endpoint(&self, path: &str) -> bool {
+        allows_endpoint(&self.endpoints, path)
+    }
+
+    pub fn allows_model(&self, model: &str) -> bool {
+        allows_model(&self.models, model)
+    }
+}
+
+/// Signs and verifies client tokens with HMAC-SHA256. A token is `v1.<payload>.<signature>`, the
+/// payload being the token's JSON, and both parts being base64url encoded.
+#[derive(Debug, Clone)]
+pub struct TokenSigner {
+    mac: Hmac<Sha256>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenError {
+    Malformed,
+    InvalidSignature,
+    Expired,
+    /// The API key that issued the token was revoked, or expired.
+    KeyRevoked,
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::Malformed => write!(f, "The client token is malformed."),
+            TokenError::InvalidSignature => write!(f, "The client token's signature is invalid."),
+            TokenError::Expired => write!(f, "The client token expired."),
+            TokenError::KeyRevoked => {
+                write!(f, "The API key that issued the client token is revoked.")
+            }
+        }
+    }
+}
+
+impl TokenSigner {
+    pub fn new(secret: &[u8]) -> Self {
+        TokenSigner {
+            mac: Hmac::new_from_slice(secret).expect("HMAC takes keys of any length"),
+        }
+    }
+
+    pub fn sign(&self, token: &ClientToken) -> String {
+        let payload = URL_SAFE_NO_PAD
+            .encode(serde_json::to_vec(token).expect("Client tokens serialize to JSON"));
+        let mut mac = self.mac.clone();
+        mac.update(payload.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{}.{}.{}", VERSION, payload, signature)
+    }
+
+    /// Checks the token's signature and expiry, at `now` seconds since the Unix epoch.
+    pub fn verify(&self, token: &str, now: i64) -> Result<ClientToken, TokenError> {
+        let mut parts = token.split('.');
+        let (Some(VERSION), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(TokenError::Malformed);
+        };
+
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TokenError::Malformed)?;
+        let mut mac = self.mac.clone();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| TokenError::InvalidSignature)?;
+
+        let token: ClientToken = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .ok_or(TokenError::Malformed)?;
+        if token.expires_at <= now {
+            return Err(TokenError::Expired);
+        }
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token() -> ClientToken {
+        ClientToken {
+            id: "token".to_string(),
+            key: "key".to_string(),
+            origin: "https://example.com".to_string(),
+            endpoints: vec!["/prompt".to_string()],
+            models: vec![],
+            limits: None,
+            issued_at: 1_000,
+            expires_at: 1_600,
+        }
+    }
+
+    #[test]
+    fn verifies_signed_tokens() {
+        let signer = TokenSigner::new(b"secret");
+
+        let signed = signer.sign(&token());
+
+        assert_eq!(signer.verify(&signed, 1_000), Ok(token()));
+        assert_eq!(signer.verify(&signed, 1_600), Err(TokenError::Expired));
+    }
+
+    #[test]
+    fn refuses_tampered_tokens() {
+        let signer = TokenSigner::new(b"secret");
+        let signed = signer.sign(&token());
+        let (_, signature) = signed.rsplit_once('.').unwrap();
+
+        let forged = TokenSigner::new(b"other secret").sign(&token());
+        assert_eq!(
+            signer.verify(&forged, 1_000),
+            Err(TokenError::InvalidSignature)
+        );
+
+        let widened = ClientToken {
+            endpoints: vec![],
+            ..token()
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&widened).unwrap());
+        assert_eq!(
+            signer.verify(&format!("v1.{}.{}", payload, signature), 1_000),
+            Err(TokenError::InvalidSignature)
+        );
+
+        assert_eq!(signer.verify("v1.abc", 1_000), Err(TokenError::Malformed));
+    }
+}
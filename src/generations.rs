@@ -0,0 +1,393 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+use uuid::Uuid;
+
+use crate::{limits::Subjects, routes::StreamEvent};
+
+/// How long the events of a generation stay available to clients resuming it, after it finishes
+/// or after its last client disconnects.
+const RESUME_WINDOW: Duration = Duration::from_secs(30);
+
+/// Tracks streaming generations and buffers their events, so they can be cancelled by id and
+/// clients that lose their connection can resume them.
+#[derive(Debug, Default)]
+pub struct Generations {
+    entries: Mutex<HashMap<Uuid, Arc<Shared>>>,
+}
+
+/// The client a generation belongs to. Only the client that started a generation may resume or
+/// cancel it, so its id alone doesn't give access to another client's output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Owner {
+    /// A server, by its API key.
+    ApiKey(String),
+    /// A page, by its origin and the key that issued its client token, if it has one. Client
+    /// tokens are short-lived, so a page that renewed its token can still resume its generations.
+    Page {
+        origin: Option<String>,
+        key: Option<String>,
+    },
+}
+
+impl Owner {
+    pub fn of(subjects: &Subjects) -> Self {
+        match &subjects.api_key {
+            Some(key) => Owner::ApiKey(key.id.clone()),
+            None => Owner::Page {
+                origin: subjects.origin().map(str::to_string),
+                key: subjects.api_key_id().map(str::to_string),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    id: Uuid,
+    owner: Owner,
+    token: CancellationToken,
+    state: Mutex<State>,
+    // Notified when an event is published and when the generation finishes.
+    published: watch::Sender<()>,
+    subscribers: watch::Sender<usize>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    events: Vec<StreamEvent>,
+    finished_at: Option<Instant>,
+}
+
+impl Generations {
+    /// Registers a new generation of `owner`. It stays resumable for [`RESUME_WINDOW`] after the
+    /// returned handle drops.
+    pub fn start(&self, owner: Owner) -> Generation {
+        let shared = Arc::new(Shared {
+            id: Uuid::new_v4(),
+            owner,
+            token: CancellationToken::new(),
+            state: Mutex::default(),
+            published: watch::Sender::new(()),
+            subscribers: watch::Sender::new(0),
+        });
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, shared| !shared.is_expired());
+        entries.insert(shared.id, shared.clone());
+
+        Generation { shared }
+    }
+
+    /// Cancels a running generation. Returns `false` if no generation of `owner` with this id is
+    /// running.
+    pub fn cancel(&self, id: &Uuid, owner: &Owner) -> bool {
+        let entries = self.entries.lock().unwrap();
+        let Some(shared) = entries.get(id).filter(|shared| shared.owner == *owner) else {
+            return false;
+        };
+        if shared.state.lock().unwrap().finished_at.is_some() {
+            return false;
+        }
+
+        shared.token.cancel();
+        true
+    }
+
+    /// Replays the events of a generation published after `last_event_id`, then follows it live.
+    /// Returns `None` if no generation of `owner` has this id, if it is no longer resumable, or if
+    /// it never published the event.
+    pub fn resume(
+        &self,
+        last_event_id: &EventId,
+        owner: &Owner,
+    ) -> Option<ReceiverStream<(EventId, StreamEvent)>> {
+        let entries = self.entries.lock().unwrap();
+        let shared = entries
+            .get(&last_event_id.generation)
+            .filter(|shared| shared.owner == *owner && !shared.is_expired())?;
+        if last_event_id.sequence > shared.state.lock().unwrap().events.len() {
+            return None;
+        }
+
+        Some(shared.clone().subscribe(last_event_id.sequence))
+    }
+}
+
+impl Shared {
+    fn is_expired(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .finished_at
+            .is_some_and(|finished_at| finished_at.elapsed() > RESUME_WINDOW)
+    }
+
+    // Streams the events after `sequence` into a channel, until the generation finishes or the
+    // receiver is dropped. `sequence` may not be past the events published so far.
+    fn subscribe(self: Arc<Self>, sequence: usize) -> ReceiverStream<(EventId, StreamEvent)> {
+        let (tx, rx) = mpsc::channel(2);
+        self.subscribers.send_modify(|count| *count += 1);
+
+        tokio::spawn(async move {
+            let mut published = self.published.subscribe();
+            let mut sequence = sequence;
+            'subscription: loop {
+                published.borrow_and_update();
+                let (events, finished) = {
+                    let state = self.state.lock().unwrap();
+                    (
+                        state.events[sequence..].to_vec(),
+                        state.finished_at.is_some(),
+                    )
+                };
+
+                for event in events {
+                    sequence += 1;
+                    let id = EventId {
+                        generation: self.id,
+                        sequence,
+                    };
+                    if tx.send((id, event)).await.is_err() {
+                        break 'subscription;
+                    }
+                }
+
+                if finished {
+                    break;
+                }
+
+                tokio::select! {
+                    _ = published.changed() => {}
+                    _ = tx.closed() => break,
+                }
+            }
+            self.subscribers.send_modify(|count| *count -= 1);
+        });
+
+        ReceiverStream::new(rx)
+    }
+}
+
+/// The producing side of a generation.
+#[derive(Debug)]
+pub struct Generation {
+    shared: Arc<Shared>,
+}
+
+impl Generation {
+    pub fn id(&self) -> Uuid {
+        self.shared.id
+    }
+
+    /// Buffers an event and sends it to the clients following the generation.
+    pub fn publish(&self, event: StreamEvent) {
+        self.shared.state.lock().unwrap().events.push(event);
+        self.shared.published.send_replace(());
+    }
+
+    /// Follows the generation from its first event.
+    pub fn subscribe(&self) -> ReceiverStream<(EventId, StreamEvent)> {
+        self.shared.clone().subscribe(0)
+    }
+
+    /// Completes when the generation is cancelled through [`Generations::cancel`].
+    pub fn cancelled(&self) -> WaitForCancellationFuture<'_> {
+        self.shared.token.cancelled()
+    }
+
+    /// Completes once no client has followed the generation for [`RESUME_WINDOW`]. The wait
+    /// starts over with each new future, so the producer has to keep polling the same one.
+    pub async fn abandoned(&self) {
+        let mut subscribers = self.shared.subscribers.subscribe();
+        loop {
+            if subscribers.wait_for(|count| *count == 0).await.is_err() {
+                return;
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(RESUME_WINDOW) => return,
+                _ = subscribers.wait_for(|count| *count > 0) => {}
+            }
+        }
+    }
+}
+
+impl Drop for Generation {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().finished_at = Some(Instant::now());
+        self.shared.published.send_replace(());
+    }
+}
+
+/// Identifies an event of a generation. It is sent as the Server-Sent Events id, and doubles as
+/// the token for resuming the generation after that event.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EventId {
+    pub generation: Uuid,
+    pub sequence: usize,
+}
+
+impl Display for EventId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.generation, self.sequence)
+    }
+}
+
+impl FromStr for EventId {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (generation, sequence) = s.split_once(':').ok_or("Missing event sequence.")?;
+        Ok(EventId {
+            generation: generation.parse().map_err(|_| "Invalid generation id.")?,
+            sequence: sequence.parse().map_err(|_| "Invalid event sequence.")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    fn chunk(text: &str) -> StreamEvent {
+        StreamEvent::chunk(text.to_string())
+    }
+
+    fn page() -> Owner {
+        Owner::Page {
+            origin: Some("https://example.com".to_string()),
+            key: None,
+        }
+    }
+
+    fn texts(events: Vec<(EventId, StreamEvent)>) -> Vec<String> {
+        events
+            .into_iter()
+            .filter_map(|(_, event)| match event {
+                StreamEvent::Chunk(chunk) => Some(chunk.text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cancels_running_generations() {
+        let generations = Generations::default();
+        let generation = generations.start(page());
+
+        assert!(!generations.cancel(&generation.id(), &Owner::ApiKey("other".to_string())));
+        assert!(!generation.shared.token.is_cancelled());
+        assert!(generations.cancel(&generation.id(), &page()));
+        assert!(generation.shared.token.is_cancelled());
+
+        drop(generation);
+    }
+
+    #[test]
+    fn does_not_cancel_finished_generations() {
+        let generations = Generations::default();
+        let id = generations.start(page()).id();
+
+        assert!(!generations.cancel(&id, &page()));
+        assert!(!generations.cancel(&Uuid::new_v4(), &page()));
+    }
+
+    #[tokio::test]
+    async fn resumes_after_the_last_event_received() {
+        let generations = Generations::default();
+        let generation = generations.start(page());
+        generation.publish(chunk("one"));
+        generation.publish(chunk("two"));
+        generation.publish(chunk("three"));
+        let last_event_id = EventId {
+            generation: generation.id(),
+            sequence: 1,
+        };
+        drop(generation);
+
+        let events = generations
+            .resume(&last_event_id, &page())
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(events[0].0.sequence, 2);
+        assert_eq!(texts(events), vec!["two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn resumes_only_the_events_of_its_own_generations() {
+        let generations = Generations::default();
+        let generation = generations.start(page());
+        generation.publish(chunk("one"));
+        let event_id = |sequence| EventId {
+            generation: generation.id(),
+            sequence,
+        };
+
+        let other_page = Owner::Page {
+            origin: Some("https://example.org".to_string()),
+            key: None,
+        };
+        assert!(generations.resume(&event_id(1), &other_page).is_none());
+        assert!(generations.resume(&event_id(2), &page()).is_none());
+        assert!(generations.resume(&event_id(1), &page()).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn abandons_generations_that_keep_publishing() {
+        let generations = Generations::default();
+        let generation = generations.start(page());
+
+        // Like the producer, waits on the upstream and on the clients at once, as long as
+        // chunks keep coming.
+        let abandoned = generation.abandoned();
+        tokio::pin!(abandoned);
+        let started = tokio::time::Instant::now();
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(1)) => generation.publish(chunk("more")),
+                _ = &mut abandoned => break,
+            }
+            assert!(started.elapsed() <= RESUME_WINDOW, "never abandoned");
+        }
+
+        assert_eq!(started.elapsed(), RESUME_WINDOW);
+    }
+
+    #[tokio::test]
+    async fn subscribers_follow_live_events() {
+        let generations = Generations::default();
+        let generation = generations.start(page());
+        let mut events = generation.subscribe();
+
+        generation.publish(chunk("one"));
+        assert_eq!(texts(vec![events.next().await.unwrap()]), vec!["one"]);
+
+        generation.publish(chunk("two"));
+        drop(generation);
+        assert_eq!(texts(events.collect().await), vec!["two"]);
+    }
+
+    #[test]
+    fn parses_event_ids() {
+        let id = EventId {
+            generation: Uuid::new_v4(),
+            sequence: 42,
+        };
+
+        assert_eq!(id.to_string().parse::<EventId>(), Ok(id));
+        assert!("42".parse::<EventId>().is_err());
+    }
+}
@@ -0,0 +1,484 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, time::Instant};
+use tracing::{error, warn};
+
+use crate::{limits::Subjects, routes::ErrorCode};
+use built_in_hybrid_server::ai::language_model::AILanguageModelUsage;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS requests (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        origin TEXT NOT NULL,
+        api_key TEXT NOT NULL,
+        provider TEXT NOT NULL,
+        model TEXT NOT NULL,
+        input_tokens INTEGER NOT NULL,
+        output_tokens INTEGER NOT NULL,
+        latency_ms INTEGER NOT NULL,
+        outcome TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS daily_usage (
+        day TEXT NOT NULL,
+        origin TEXT NOT NULL,
+        api_key TEXT NOT NULL,
+        provider TEXT NOT NULL,
+        model TEXT NOT NULL,
+        requests INTEGER NOT NULL,
+        failures INTEGER NOT NULL,
+        input_tokens INTEGER NOT NULL,
+        output_tokens INTEGER NOT NULL,
+        latency_ms INTEGER NOT NULL,
+        PRIMARY KEY (day, origin, api_key, provider, model)
+    );
+    CREATE TABLE IF NOT EXISTS rollups (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        last_request_id INTEGER NOT NULL
+    );
+    INSERT OR IGNORE INTO rollups (id, last_request_id) VALUES (0, 0);
+";
+
+// Entries waiting to be written. Past this, entries are dropped rather than slowing requests down.
+const MAX_PENDING_ENTRIES: usize = 4096;
+const MAX_BATCH: usize = 256;
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// How long requests are kept individually once they are rolled up.
+const RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Records every prompt request in a SQLite database, to attribute the provider's costs to the
+/// origins and API keys that caused them.
+///
+/// Requests are written in batches by a background task, and periodically rolled up into daily
+/// usage per origin, API key, provider and model.
+#[derive(Debug, Clone)]
+pub struct Ledger {
+    connection: Arc<Mutex<Connection>>,
+    entries: mpsc::Sender<LedgerEntry>,
+}
+
+impl Ledger {
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        let connection = Arc::new(Mutex::new(connection));
+
+        let (entries, receiver) = mpsc::channel(MAX_PENDING_ENTRIES);
+        tokio::spawn(write_entries(connection.clone(), receiver));
+        Ok(Ledger {
+            connection,
+            entries,
+        })
+    }
+
+    /// Starts the entry of a request to `provider`'s `model`. The entry is recorded when it is
+    /// dropped, as aborted unless it was marked as succeeded or failed.
+    pub fn start(
+        &self,
+        subjects: &Subjects,
+        provider: &'static str,
+        model: &'static str,
+    ) -> PendingEntry {
+        PendingEntry {
+            entries: self.entries.clone(),
+            started: Instant::now(),
+            entry: LedgerEntry {
+                timestamp: unix_now(),
+                origin: subjects.origin().unwrap_or_default().to_string(),
+                api_key: subjects.api_key_id().unwrap_or_default().to_string(),
+                provider,
+                model,
+                usage: AILanguageModelUsage::default(),
+                latency: Duration::ZERO,
+                outcome: Some(ErrorCode::Aborted),
+            },
+        }
+    }
+
+    /// The usage of each origin and API key on each day, including the requests recorded so far.
+    pub async fn daily_usage(&self, query: UsageQuery) -> rusqlite::Result<Vec<DailyUsage>> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().unwrap();
+            rollup(&mut connection, unix_now())?;
+            daily_usage(&connection, &query)
+        })
+        .await
+        .expect("Ledger queries don't panic")
+    }
+}
+
+// A request, as recorded in the ledger.
+#[derive(Debug)]
+struct LedgerEntry {
+    timestamp: i64,
+    origin: String,
+    // Empty when the request wasn't made with an API key.
+    api_key: String,
+    provider: &'static str,
+    model: &'static str,
+    usage: AILanguageModelUsage,
+    latency: Duration,
+    // `None` when the request succeeded.
+    outcome: Option<ErrorCode>,
+}
+
+/// The entry of a request that is still running.
+#[derive(Debug)]
+pub struct PendingEntry {
+    entries: mpsc::Sender<LedgerEntry>,
+    started: Instant,
+    entry: LedgerEntry,
+}
+
+impl PendingEntry {
+    pub fn set_usage(&mut self, usage: AILanguageModelUsage) {
+        self.entry.usage = usage;
+    }
+
+    pub fn succeeded(&mut self) {
+        self.entry.outcome = None;
+    }
+
+    pub fn failed(&mut self, code: ErrorCode) {
+        self.entry.outcome = Some(code);
+    }
+}
+
+impl Drop for PendingEntry {
+    fn drop(&mut self) {
+        let entry = LedgerEntry {
+            latency: self.started.elapsed(),
+            origin: std::mem::take(&mut self.entry.origin),
+            api_key: std::mem::take(&mut self.entry.api_key),
+            ..self.entry
+        };
+        if self.entries.try_send(entry).is_err() {
+            warn!("The ledger is falling behind, dropped a request entry.");
+        }
+    }
+}
+
+// Writes the entries as they come, and rolls them up every `ROLLUP_INTERVAL`.
+async fn write_entries(
+    connection: Arc<Mutex<Connection>>,
+    mut receiver: mpsc::Receiver<LedgerEntry>,
+) {
+    let mut rollups = tokio::time::interval(ROLLUP_INTERVAL);
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    loop {
+        tokio::select! {
+            received = receiver.recv_many(&mut batch, MAX_BATCH) => {
+                if received == 0 {
+                    return;
+                }
+                let entries = std::mem::take(&mut batch);
+                let connection = connection.clone();
+                let written = tokio::task::spawn_blocking(move || {
+                    insert(&mut connection.lock().unwrap(), &entries)
+                });
+                if let Ok(Err(e)) = written.await {
+                    error!("Failed to write usage to the ledger: {}", e);
+                }
+            }
+            _ = rollups.tick() => {
+                let connection = connection.clone();
+                let rolled_up = tokio::task::spawn_blocking(move || {
+                    rollup(&mut connection.lock().unwrap(), unix_now())
+                });
+                if let Ok(Err(e)) = rolled_up.await {
+                    error!("Failed to roll up the ledger: {}", e);
+                }
+            }
+        }
+    }
+}
+
+fn insert(connection: &mut Connection, entries: &[LedgerEntry]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction.prepare_cached(
+            "INSERT INTO requests (timestamp, origin, api_key, provider, model, input_tokens,
+                output_tokens, latency_ms, outcome)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )?;
+        for entry in entries {
+            statement.execute(params![
+                entry.timestamp,
+                entry.origin,
+                entry.api_key,
+                entry.provider,
+                entry.model,
+                entry.usage.input_tokens,
+                entry.usage.output_tokens,
+                entry.latency.as_millis() as i64,
+                outcome(entry.outcome),
+            ])?;
+        }
+    }
+    transaction.commit()
+}
+
+// Adds the requests recorded since the last rollup to the daily usage, and deletes the requests
+// older than `RETENTION`. The last request rolled up is kept, as SQLite would otherwise reuse the
+// ids of deleted requests, below the watermark, and later requests would never be rolled up.
+fn rollup(connection: &mut Connection, now: i64) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    let last_request_id: i64 =
+        transaction.query_row("SELECT last_request_id FROM rollups", [], |row| row.get(0))?;
+    let max_request_id: i64 =
+        transaction.query_row("SELECT coalesce(max(id), 0) FROM requests", [], |row| {
+            row.get(0)
+        })?;
+
+    transaction.execute(
+        "INSERT INTO daily_usage (day, origin, api_key, provider, model, requests, failures,
+            input_tokens, output_tokens, latency_ms)
+        SELECT date(timestamp, 'unixepoch'), origin, api_key, provider, model, count(*),
+            sum(outcome <> 'ok'), sum(input_tokens), sum(output_tokens), sum(latency_ms)
+        FROM requests
+        WHERE id > ?1 AND id <= ?2
+        GROUP BY 1, 2, 3, 4, 5
+        ON CONFLICT (day, origin, api_key, provider, model) DO UPDATE SET
+            requests = requests + excluded.requests,
+            failures = failures + excluded.failures,
+            input_tokens = input_tokens + excluded.input_tokens,
+            output_tokens = output_tokens + excluded.output_tokens,
+            latency_ms = latency_ms + excluded.latency_ms",
+        params![last_request_id, max_request_id],
+    )?;
+    transaction.execute(
+        "UPDATE rollups SET last_request_id = ?1",
+        params![max_request_id],
+    )?;
+    transaction.execute(
+        "DELETE FROM requests WHERE id < ?1 AND timestamp < ?2",
+        params![max_request_id, now - RETENTION.as_secs() as i64],
+    )?;
+    transaction.commit()
+}
+
+/// Filters the daily usage. Days are formatted as `YYYY-MM-DD`, in UTC, and both ends are
+/// included.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageQuery {
+    pub origin: Option<String>,
+    pub api_key: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DailyUsage {
+    pub day: String,
+    pub origin: String,
+    /// The id of the API key the requests were made with, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    pub requests: u64,
+    pub failures: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub average_latency_ms: u64,
+}
+
+fn daily_usage(connection: &Connection, query: &UsageQuery) -> rusqlite::Result<Vec<DailyUsage>> {
+    let mut statement = connection.prepare_cached(
+        "SELECT day, origin, api_key, sum(requests), sum(failures), sum(input_tokens),
+            sum(output_tokens), sum(latency_ms)
+        FROM daily_usage
+        WHERE (?1 IS NULL OR origin = ?1) AND (?2 IS NULL OR api_key = ?2)
+            AND (?3 IS NULL OR day >= ?3) AND (?4 IS NULL OR day <= ?4)
+        GROUP BY day, origin, api_key
+        ORDER BY day, origin, api_key",
+    )?;
+    statement
+        .query_map(
+            params![query.origin, query.api_key, query.from, query.to],
+            |row| {
+                let requests: i64 = row.get(3)?;
+                let latency_ms: i64 = row.get(7)?;
+                Ok(DailyUsage {
+                    day: row.get(0)?,
+                    origin: row.get(1)?,
+                    api_key: Some(row.get::<_, String>(2)?).filter(|key| !key.is_empty()),
+                    requests: requests as u64,
+                    failures: row.get::<_, i64>(4)? as u64,
+                    input_tokens: row.get::<_, i64>(5)? as u64,
+                    output_tokens: row.get::<_, i64>(6)? as u64,
+                    average_latency_ms: (latency_ms / requests.max(1)) as u64,
+                })
+            },
+        )?
+        .collect()
+}
+
+fn outcome(code: Option<ErrorCode>) -> String {
+    match code {
+        Some(code) => serde_json::to_value(code)
+            .ok()
+            .and_then(|code| code.as_str().map(str::to_string))
+            .unwrap_or_default(),
+        None => "ok".to_string(),
+    }
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2025-06-01T12:00:00Z
+    const JUNE_FIRST: i64 = 1_748_779_200;
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn entry(timestamp: i64, origin: &str, outcome: Option<ErrorCode>) -> LedgerEntry {
+        LedgerEntry {
+            timestamp,
+            origin: origin.to_string(),
+            api_key: String::new(),
+            provider: "gemini",
+            model: "gemini-2.0-flash",
+            usage: AILanguageModelUsage {
+                input_tokens: 100,
+                output_tokens: 20,
+                total_tokens: 120,
+            },
+            latency: Duration::from_millis(300),
+            outcome,
+        }
+    }
+
+    fn ledger() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        connection
+    }
+
+    #[test]
+    fn rolls_up_usage_by_origin_and_day() {
+        let mut connection = ledger();
+        insert(
+            &mut connection,
+            &[
+                entry(JUNE_FIRST, "https://a.example", None),
+                entry(
+                    JUNE_FIRST + 60,
+                    "https://a.example",
+                    Some(ErrorCode::Timeout),
+                ),
+                entry(JUNE_FIRST, "https://b.example", None),
+                entry(JUNE_FIRST + DAY, "https://a.example", None),
+            ],
+        )
+        .unwrap();
+        rollup(&mut connection, JUNE_FIRST + DAY).unwrap();
+
+        let usage = daily_usage(
+            &connection,
+            &UsageQuery {
+                origin: Some("https://a.example".to_string()),
+                ..UsageQuery::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            usage,
+            vec![
+                DailyUsage {
+                    day: "2025-06-01".to_string(),
+                    origin: "https://a.example".to_string(),
+                    api_key: None,
+                    requests: 2,
+                    failures: 1,
+                    input_tokens: 200,
+                    output_tokens: 40,
+                    average_latency_ms: 300,
+                },
+                DailyUsage {
+                    day: "2025-06-02".to_string(),
+                    origin: "https://a.example".to_string(),
+                    api_key: None,
+                    requests: 1,
+                    failures: 0,
+                    input_tokens: 100,
+                    output_tokens: 20,
+                    average_latency_ms: 300,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rolls_up_each_request_once() {
+        let mut connection = ledger();
+        insert(
+            &mut connection,
+            &[entry(JUNE_FIRST, "https://a.example", None)],
+        )
+        .unwrap();
+        rollup(&mut connection, JUNE_FIRST).unwrap();
+        insert(
+            &mut connection,
+            &[entry(JUNE_FIRST, "https://a.example", None)],
+        )
+        .unwrap();
+        rollup(&mut connection, JUNE_FIRST).unwrap();
+        rollup(&mut connection, JUNE_FIRST).unwrap();
+
+        let usage = daily_usage(&connection, &UsageQuery::default()).unwrap();
+        assert_eq!(usage[0].requests, 2);
+    }
+
+    #[test]
+    fn deletes_requests_after_the_retention_period() {
+        let mut connection = ledger();
+        insert(
+            &mut connection,
+            &[
+                entry(JUNE_FIRST, "https://a.example", None),
+                entry(JUNE_FIRST, "https://a.example", None),
+            ],
+        )
+        .unwrap();
+        rollup(&mut connection, JUNE_FIRST + 31 * DAY).unwrap();
+
+        let requests: i64 = connection
+            .query_row("SELECT count(*) FROM requests", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(requests, 1);
+        let usage = daily_usage(&connection, &UsageQuery::default()).unwrap();
+        assert_eq!(usage[0].requests, 2);
+    }
+
+    #[test]
+    fn rolls_up_requests_recorded_after_the_old_ones_are_deleted() {
+        let mut connection = ledger();
+        for _ in 0..2 {
+            insert(
+                &mut connection,
+                &[entry(JUNE_FIRST, "https://a.example", None)],
+            )
+            .unwrap();
+            rollup(&mut connection, JUNE_FIRST + 31 * DAY).unwrap();
+        }
+
+        let usage = daily_usage(&connection, &UsageQuery::default()).unwrap();
+        assert_eq!(usage[0].requests, 2);
+    }
+}
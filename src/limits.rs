@@ -0,0 +1,890 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use crate::{api_keys::ApiKey, client_tokens::ClientToken, origins::OriginPolicy};
+use built_in_hybrid_server::ai::language_model::{AILanguageModelGuardrails, AILanguageModelUsage};
+
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+/// The headers describing the tightest limit a request was checked against.
+pub const RATELIMIT_HEADERS: [HeaderName; 4] = [
+    RATELIMIT_LIMIT,
+    RATELIMIT_REMAINING,
+    RATELIMIT_RESET,
+    RATELIMIT_POLICY,
+];
+
+/// Who a request is charged to. A request is charged to each of its subjects, and is refused if
+/// any of them is over its limits.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Subject {
+    Origin(String),
+    ApiKey(String),
+    ClientToken(String),
+    Ip(IpAddr),
+}
+
+impl Display for Subject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Subject::Origin(origin) => write!(f, "origin {}", origin),
+            Subject::ApiKey(key) => write!(f, "API key {}", key),
+            Subject::ClientToken(token) => write!(f, "client token {}", token),
+            Subject::Ip(ip) => write!(f, "IP {}", ip),
+        }
+    }
+}
+
+/// The subjects a request is charged to.
+#[derive(Debug, Clone, Default)]
+pub struct Subjects {
+    pub subjects: Vec<Subject>,
+    /// The API key the request was made with, whose own limits replace the default ones.
+    pub api_key: Option<Arc<ApiKey>>,
+    /// The client token the request was made with, whose limits are those of its
+    /// `Subject::ClientToken`.
+    pub client_token: Option<Arc<ClientToken>>,
+    /// The policy of the request's origin, whose limits replace the default origin limits.
+    pub origin_policy: Option<Arc<OriginPolicy>>,
+    /// The guardrails of the request's origin and API key.
+    pub guardrails: AILanguageModelGuardrails,
+    /// The proof-of-work allowance the request spends a prompt from, when it has neither.
+    pub allowance: Option<String>,
+}
+
+impl Subjects {
+    pub fn origin(&self) -> Option<&str> {
+        self.subjects.iter().find_map(|subject| match subject {
+            Subject::Origin(origin) => Some(origin.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.subjects.iter().find_map(|subject| match subject {
+            Subject::Ip(ip) => Some(*ip),
+            _ => None,
+        })
+    }
+
+    /// Whether the request was made without an API key or a client token.
+    pub fn is_anonymous(&self) -> bool {
+        self.api_key.is_none() && self.client_token.is_none()
+    }
+
+    /// The id of the API key the request was made with, directly or through a client token.
+    pub fn api_key_id(&self) -> Option<&str> {
+        match (&self.api_key, &self.client_token) {
+            (Some(key), _) => Some(&key.id),
+            (None, Some(token)) => Some(&token.key),
+            (None, None) => None,
+        }
+    }
+
+    /// Whether the request's API key, client token and origin allow `model`.
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.api_key
+            .as_ref()
+            .is_none_or(|key| key.allows_model(model))
+            && self
+                .client_token
+                .as_ref()
+                .is_none_or(|token| token.allows_model(model))
+            && self
+                .origin_policy
+                .as_ref()
+                .is_none_or(|policy| policy.allows_model(model))
+    }
+
+    fn quota<'a>(&'a self, subject: &Subject, config: &'a LimitsConfig) -> &'a Quota {
+        let limits = match subject {
+            Subject::ApiKey(_) => self.api_key.as_ref().and_then(|key| key.limits.as_ref()),
+            Subject::ClientToken(_) => self
+                .client_token
+                .as_ref()
+                .and_then(|token| token.limits.as_ref()),
+            Subject::Origin(_) => self
+                .origin_policy
+                .as_ref()
+                .and_then(|policy| policy.limits.as_ref()),
+            Subject::Ip(_) => None,
+        };
+        limits.unwrap_or_else(|| config.quota(subject))
+    }
+}
+
+/// The limits of a subject. Rates are counted over a sliding minute, and budgets, in input plus
+/// output tokens, over the current UTC day and month. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Quota {
+    pub requests_per_minute: Option<u64>,
+    pub input_tokens_per_minute: Option<u64>,
+    pub output_tokens_per_minute: Option<u64>,
+    pub tokens_per_day: Option<u64>,
+    pub tokens_per_month: Option<u64>,
+}
+
+const UNLIMITED: Quota = Quota {
+    requests_per_minute: None,
+    input_tokens_per_minute: None,
+    output_tokens_per_minute: None,
+    tokens_per_day: None,
+    tokens_per_month: None,
+};
+
+/// The quotas of each kind of subject.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LimitsConfig {
+    pub origin: Quota,
+    pub api_key: Quota,
+    pub ip: Quota,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        let site = Quota {
+            requests_per_minute: Some(600),
+            input_tokens_per_minute: Some(1_000_000),
+            output_tokens_per_minute: Some(200_000),
+            tokens_per_day: Some(20_000_000),
+            tokens_per_month: Some(300_000_000),
+        };
+        LimitsConfig {
+            origin: site,
+            api_key: site,
+            ip: Quota {
+                requests_per_minute: Some(30),
+                input_tokens_per_minute: Some(100_000),
+                output_tokens_per_minute: Some(20_000),
+                tokens_per_day: Some(1_000_000),
+                tokens_per_month: Some(10_000_000),
+            },
+        }
+    }
+}
+
+impl LimitsConfig {
+    fn quota(&self, subject: &Subject) -> &Quota {
+        match subject {
+            Subject::Origin(_) => &self.origin,
+            Subject::ApiKey(_) => &self.api_key,
+            // Client tokens only have the limits they were issued with.
+            Subject::ClientToken(_) => &UNLIMITED,
+            Subject::Ip(_) => &self.ip,
+        }
+    }
+}
+
+/// Enforces the rate limits and budgets of each subject.
+///
+/// Requests are admitted on an estimate of their input tokens, counted with the tokenizer, and
+/// charged for the input and output tokens the provider reports once they complete.
+#[derive(Debug)]
+pub struct Limiter {
+    config: LimitsConfig,
+    usage: Mutex<Usage>,
+}
+
+impl Limiter {
+    pub fn new(config: LimitsConfig) -> Self {
+        Limiter {
+            config,
+            usage: Mutex::default(),
+        }
+    }
+
+    /// Admits a request estimated to use `input_tokens`, charging the estimate to its subjects.
+    /// The charge is corrected with the actual usage when the returned reservation is settled.
+    pub fn reserve(
+        self: &Arc<Self>,
+        subjects: &Subjects,
+        input_tokens: u64,
+    ) -> Result<Reservation, LimitExceeded> {
+        self.reserve_at(Moment::now(), subjects, input_tokens)
+    }
+
+    fn reserve_at(
+        self: &Arc<Self>,
+        now: Moment,
+        subjects: &Subjects,
+        input_tokens: u64,
+    ) -> Result<Reservation, LimitExceeded> {
+        let mut usage = self.usage.lock().unwrap();
+        let Usage { rates, budgets } = &mut *usage;
+        rates.prune(|rates| rates.is_active(now));
+        budgets.prune(|budgets| budgets.is_active(now));
+
+        let mut tightest: Option<RateLimitStatus> = None;
+        for subject in &subjects.subjects {
+            let quota = subjects.quota(subject, &self.config);
+            let rates = rates.get(subject);
+            let budgets = budgets.get(subject);
+            for check in checks(rates, budgets, quota, now, input_tokens) {
+                if check.requested > check.status.remaining {
+                    return Err(LimitExceeded {
+                        subject: subject.clone(),
+                        kind: check.kind,
+                        status: check.status,
+                    });
+                }
+                let status = RateLimitStatus {
+                    remaining: check.status.remaining - check.requested,
+                    ..check.status
+                };
+                if tightest.is_none_or(|tightest| status.is_tighter_than(&tightest)) {
+                    tightest = Some(status);
+                }
+            }
+        }
+
+        for subject in &subjects.subjects {
+            let rates = rates.get(subject);
+            rates.requests.add(now.instant, 1);
+            rates.charge(now, input_tokens as i64, 0);
+            budgets.get(subject).charge(now, input_tokens as i64);
+        }
+
+        Ok(Reservation {
+            limiter: self.clone(),
+            subjects: subjects.clone(),
+            input_tokens,
+            status: tightest,
+        })
+    }
+}
+
+// The usage of every subject. The rates of a subject only count for a couple of minutes after
+// its last request, but its budgets for the rest of the month, so they are kept apart, for idle
+// subjects to be forgotten.
+#[derive(Debug, Default)]
+struct Usage {
+    rates: Tracked<Rates>,
+    budgets: Tracked<Budgets>,
+}
+
+// The fewest entries pruned of those that no longer count.
+const MIN_PRUNED: usize = 10_000;
+
+// Entries by subject. Entries that no longer count are pruned whenever their number doubles, so
+// pruning stays amortized over the requests that added them.
+#[derive(Debug)]
+struct Tracked<T> {
+    entries: HashMap<Subject, T>,
+    prune_at: usize,
+}
+
+impl<T> Default for Tracked<T> {
+    fn default() -> Self {
+        Tracked {
+            entries: HashMap::new(),
+            prune_at: MIN_PRUNED,
+        }
+    }
+}
+
+impl<T: Default> Tracked<T> {
+    fn get(&mut self, subject: &Subject) -> &mut T {
+        self.entries.entry(subject.clone()).or_default()
+    }
+
+    fn prune(&mut self, mut counts: impl FnMut(&mut T) -> bool) {
+        if self.entries.len() < self.prune_at {
+            return;
+        }
+        self.entries.retain(|_, entry| counts(entry));
+        self.prune_at = (self.entries.len() * 2).max(MIN_PRUNED);
+    }
+}
+
+// The rates of a subject, over a sliding minute.
+#[derive(Debug, Default)]
+struct Rates {
+    requests: SlidingWindow,
+    input_tokens: SlidingWindow,
+    output_tokens: SlidingWindow,
+}
+
+impl Rates {
+    fn charge(&mut self, now: Moment, input_tokens: i64, output_tokens: i64) {
+        self.input_tokens.add(now.instant, input_tokens);
+        self.output_tokens.add(now.instant, output_tokens);
+    }
+
+    // Whether forgetting these rates would lift a limit.
+    fn is_active(&mut self, now: Moment) -> bool {
+        self.requests.used(now.instant) > 0
+            || self.input_tokens.used(now.instant) > 0
+            || self.output_tokens.used(now.instant) > 0
+    }
+}
+
+// The budgets of a subject, over the current UTC day and month.
+#[derive(Debug, Default)]
+struct Budgets {
+    day: Budget,
+    month: Budget,
+}
+
+impl Budgets {
+    fn charge(&mut self, now: Moment, tokens: i64) {
+        self.day.add(now.day().0, tokens);
+        self.month.add(now.month().0, tokens);
+    }
+
+    // Whether forgetting these budgets would lift a limit.
+    fn is_active(&mut self, now: Moment) -> bool {
+        self.month.used(now.month().0) > 0
+    }
+}
+
+// A limit to check before admitting a request.
+struct Check {
+    kind: LimitKind,
+    requested: u64,
+    status: RateLimitStatus,
+}
+
+fn checks(
+    rates: &mut Rates,
+    budgets: &mut Budgets,
+    quota: &Quota,
+    now: Moment,
+    input_tokens: u64,
+) -> Vec<Check> {
+    let day = now.day();
+    let month = now.month();
+    let limits = [
+        (LimitKind::Requests, quota.requests_per_minute, 1),
+        (
+            LimitKind::InputTokens,
+            quota.input_tokens_per_minute,
+            input_tokens,
+        ),
+        // The output is unknown until the response completes, so this only checks that some of
+        // the limit is left.
+        (LimitKind::OutputTokens, quota.output_tokens_per_minute, 1),
+        (LimitKind::DailyBudget, quota.tokens_per_day, input_tokens),
+        (
+            LimitKind::MonthlyBudget,
+            quota.tokens_per_month,
+            input_tokens,
+        ),
+    ];
+
+    limits
+        .into_iter()
+        .filter_map(|(kind, limit, requested)| {
+            let limit = limit?;
+            let (used, reset, window) = match kind {
+                LimitKind::Requests => (
+                    rates.requests.used(now.instant),
+                    rates.requests.reset(now.instant),
+                    MINUTE,
+                ),
+                LimitKind::InputTokens => (
+                    rates.input_tokens.used(now.instant),
+                    rates.input_tokens.reset(now.instant),
+                    MINUTE,
+                ),
+                LimitKind::OutputTokens => (
+                    rates.output_tokens.used(now.instant),
+                    rates.output_tokens.reset(now.instant),
+                    MINUTE,
+                ),
+                LimitKind::DailyBudget => (budgets.day.used(day.0), day.1, DAY),
+                LimitKind::MonthlyBudget => (budgets.month.used(month.0), month.1, month.2),
+            };
+            Some(Check {
+                kind,
+                requested,
+                status: RateLimitStatus {
+                    limit,
+                    remaining: limit.saturating_sub(used),
+                    reset,
+                    window,
+                },
+            })
+        })
+        .collect()
+}
+
+// Counts events over a sliding window, approximated from the counts of the current and previous
+// fixed windows.
+#[derive(Debug, Default)]
+struct SlidingWindow {
+    current_start: Option<Instant>,
+    current: u64,
+    previous: u64,
+}
+
+impl SlidingWindow {
+    fn rotate(&mut self, now: Instant) {
+        let start = *self.current_start.get_or_insert(now);
+        let elapsed = now.duration_since(start);
+        if elapsed >= MINUTE * 2 {
+            self.previous = 0;
+            self.current = 0;
+            self.current_start = Some(now);
+        } else if elapsed >= MINUTE {
+            self.previous = self.current;
+            self.current = 0;
+            self.current_start = Some(start + MINUTE);
+        }
+    }
+
+    fn used(&mut self, now: Instant) -> u64 {
+        self.rotate(now);
+        let elapsed = now.duration_since(self.current_start.unwrap_or(now));
+        let previous_weight = 1.0 - elapsed.as_secs_f64() / MINUTE.as_secs_f64();
+        self.current + (self.previous as f64 * previous_weight).ceil() as u64
+    }
+
+    fn add(&mut self, now: Instant, amount: i64) {
+        self.rotate(now);
+        self.current = self.current.saturating_add_signed(amount);
+    }
+
+    // When the previous window stops counting, which is when most of the limit frees up.
+    fn reset(&self, now: Instant) -> Duration {
+        let start = self.current_start.unwrap_or(now);
+        (start + MINUTE).saturating_duration_since(now)
+    }
+}
+
+// Counts tokens over a calendar period.
+#[derive(Debug, Default)]
+struct Budget {
+    period: i64,
+    used: u64,
+}
+
+impl Budget {
+    fn used(&mut self, period: i64) -> u64 {
+        if self.period != period {
+            self.period = period;
+            self.used = 0;
+        }
+        self.used
+    }
+
+    fn add(&mut self, period: i64, amount: i64) {
+        self.used(period);
+        self.used = self.used.saturating_add_signed(amount);
+    }
+}
+
+// A point in time, both on the monotonic clock used by the rate windows, and on the calendar used
+// by the budgets.
+#[derive(Debug, Clone, Copy)]
+struct Moment {
+    instant: Instant,
+    unix: Duration,
+}
+
+impl Moment {
+    fn now() -> Self {
+        Moment {
+            instant: Instant::now(),
+            unix: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+        }
+    }
+
+    fn days(&self) -> i64 {
+        (self.unix.as_secs() / DAY.as_secs()) as i64
+    }
+
+    // The current UTC day, and the time left in it.
+    fn day(&self) -> (i64, Duration) {
+        let end = Duration::from_secs((self.days() as u64 + 1) * DAY.as_secs());
+        (self.days(), end - self.unix)
+    }
+
+    // The current UTC month, the time left in it, and its length.
+    fn month(&self) -> (i64, Duration, Duration) {
+        let (year, month, _) = civil_from_days(self.days());
+        let start = days_from_civil(year, month, 1);
+        let end = if month == 12 {
+            days_from_civil(year + 1, 1, 1)
+        } else {
+            days_from_civil(year, month + 1, 1)
+        };
+        let end_secs = Duration::from_secs(end as u64 * DAY.as_secs());
+        (
+            year * 12 + month - 1,
+            end_secs - self.unix,
+            DAY * (end - start) as u32,
+        )
+    }
+}
+
+// Converts a date to days since the Unix epoch, and back.
+// See https://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// An admitted request's charge. Requests that don't complete, and so report no usage, stay
+/// charged for their estimate, unless they are settled with [`Reservation::settle_partial`].
+#[derive(Debug)]
+pub struct Reservation {
+    limiter: Arc<Limiter>,
+    subjects: Subjects,
+    input_tokens: u64,
+    status: Option<RateLimitStatus>,
+}
+
+impl Reservation {
+    pub fn subjects(&self) -> &Subjects {
+        &self.subjects
+    }
+
+    /// The tightest of the limits the request was checked against.
+    pub fn status(&self) -> Option<RateLimitStatus> {
+        self.status
+    }
+
+    /// Charges the request for the usage the provider reported, instead of the estimate.
+    pub fn settle(self, usage: &AILanguageModelUsage) {
+        let now = Moment::now();
+        let input_tokens = i64::from(usage.input_tokens) - self.input_tokens as i64;
+        let output_tokens = i64::from(usage.output_tokens);
+
+        let mut usage = self.limiter.usage.lock().unwrap();
+        for subject in &self.subjects.subjects {
+            // Either may have been pruned while the request ran, long enough for its rates to
+            // stop counting.
+            usage
+                .rates
+                .get(subject)
+                .charge(now, input_tokens, output_tokens);
+            usage
+                .budgets
+                .get(subject)
+                .charge(now, input_tokens + output_tokens);
+        }
+    }
+
+    /// Charges a request cut short for the usage the provider reported so far, if any, counting
+    /// at least `delivered_tokens` of output. Providers report the output tokens with the last
+    /// chunks of a response, which a request cut short may never get. Without reported usage, the
+    /// input stays charged for its estimate.
+    pub fn settle_partial(self, usage: Option<&AILanguageModelUsage>, delivered_tokens: u32) {
+        let input_tokens = usage.map_or(self.input_tokens as u32, |usage| usage.input_tokens);
+        let output_tokens = usage
+            .map_or(0, |usage| usage.output_tokens)
+            .max(delivered_tokens);
+        self.settle(&AILanguageModelUsage {
+            input_tokens,
+            output_tokens,
+            total_tokens: input_tokens + output_tokens,
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitKind {
+    Requests,
+    InputTokens,
+    OutputTokens,
+    DailyBudget,
+    MonthlyBudget,
+}
+
+impl LimitKind {
+    pub fn is_budget(&self) -> bool {
+        matches!(self, LimitKind::DailyBudget | LimitKind::MonthlyBudget)
+    }
+}
+
+/// A request refused because one of its subjects is over a limit.
+#[derive(Debug)]
+pub struct LimitExceeded {
+    pub subject: Subject,
+    pub kind: LimitKind,
+    pub status: RateLimitStatus,
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let limit = match self.kind {
+            LimitKind::Requests => "request rate limit",
+            LimitKind::InputTokens => "input token rate limit",
+            LimitKind::OutputTokens => "output token rate limit",
+            LimitKind::DailyBudget => "daily token budget",
+            LimitKind::MonthlyBudget => "monthly token budget",
+        };
+        write!(
+            f,
+            "The {} of this {} is used up.",
+            limit,
+            self.subject.kind()
+        )
+    }
+}
+
+impl Subject {
+    fn kind(&self) -> &'static str {
+        match self {
+            Subject::Origin(_) => "origin",
+            Subject::ApiKey(_) => "API key",
+            Subject::ClientToken(_) => "client token",
+            Subject::Ip(_) => "IP address",
+        }
+    }
+}
+
+/// The state of a limit, sent in the `RateLimit-*` headers.
+/// See https://datatracker.ietf.org/doc/draft-ietf-httpapi-ratelimit-headers/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitStatus {
+    pub limit: u64,
+    pub remaining: u64,
+    /// How long until the limit frees up.
+    pub reset: Duration,
+    pub window: Duration,
+}
+
+impl RateLimitStatus {
+    fn is_tighter_than(&self, other: &RateLimitStatus) -> bool {
+        (self.remaining as f64 / self.limit.max(1) as f64)
+            < (other.remaining as f64 / other.limit.max(1) as f64)
+    }
+
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        let reset = self.reset.as_secs() + u64::from(self.reset.subsec_nanos() > 0);
+        headers.insert(RATELIMIT_LIMIT, self.limit.into());
+        headers.insert(RATELIMIT_REMAINING, self.remaining.into());
+        headers.insert(RATELIMIT_RESET, reset.into());
+        headers.insert(
+            RATELIMIT_POLICY,
+            HeaderValue::from_str(&format!("{};w={}", self.limit, self.window.as_secs()))
+                .expect("Policies are valid header values"),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(quota: Quota) -> Arc<Limiter> {
+        Arc::new(Limiter::new(LimitsConfig {
+            origin: quota,
+            api_key: quota,
+            ip: quota,
+        }))
+    }
+
+    fn subjects() -> Subjects {
+        Subjects {
+            subjects: vec![Subject::Origin("https://example.com".to_string())],
+            api_key: None,
+            client_token: None,
+            origin_policy: None,
+            guardrails: Default::default(),
+            allowance: None,
+        }
+    }
+
+    fn at(moment: Moment, elapsed: Duration) -> Moment {
+        Moment {
+            instant: moment.instant + elapsed,
+            unix: moment.unix + elapsed,
+        }
+    }
+
+    #[test]
+    fn limits_requests_over_a_sliding_minute() {
+        let limiter = limiter(Quota {
+            requests_per_minute: Some(2),
+            ..Default::default()
+        });
+        let now = Moment::now();
+
+        assert!(limiter.reserve_at(now, &subjects(), 0).is_ok());
+        assert!(limiter.reserve_at(now, &subjects(), 0).is_ok());
+        let exceeded = limiter.reserve_at(now, &subjects(), 0).unwrap_err();
+        assert_eq!(exceeded.kind, LimitKind::Requests);
+        assert_eq!(exceeded.status.remaining, 0);
+
+        // Half way through the next minute, half of the previous minute still counts.
+        let later = at(now, Duration::from_secs(90));
+        assert!(limiter.reserve_at(later, &subjects(), 0).is_ok());
+        assert!(limiter.reserve_at(later, &subjects(), 0).is_err());
+    }
+
+    #[test]
+    fn forgets_idle_rates_before_budgets() {
+        let limiter = limiter(Quota::default());
+        // Mid-month, so the month doesn't end before the budgets are pruned.
+        let now = Moment {
+            instant: Instant::now(),
+            unix: DAY * days_from_civil(2026, 10, 15) as u32,
+        };
+        let ip = |n: u32| Subjects {
+            subjects: vec![Subject::Ip(IpAddr::from(n.to_be_bytes()))],
+            ..subjects()
+        };
+        for n in 0..MIN_PRUNED as u32 {
+            limiter.reserve_at(now, &ip(n), 10).unwrap();
+        }
+
+        // Once idle, the rates no longer count, but the tokens still do for the month.
+        let later = at(now, Duration::from_secs(180));
+        limiter.reserve_at(later, &ip(u32::MAX), 10).unwrap();
+        let usage = limiter.usage.lock().unwrap();
+        assert_eq!(usage.rates.entries.len(), 1);
+        assert_eq!(usage.rates.prune_at, MIN_PRUNED);
+        assert_eq!(usage.budgets.entries.len(), MIN_PRUNED + 1);
+        assert_eq!(usage.budgets.prune_at, MIN_PRUNED * 2);
+    }
+
+    #[test]
+    fn settles_on_the_reported_usage() {
+        let limiter = limiter(Quota {
+            output_tokens_per_minute: Some(100),
+            tokens_per_day: Some(1_000),
+            ..Default::default()
+        });
+
+        let reservation = limiter.reserve(&subjects(), 500).unwrap();
+        reservation.settle(&AILanguageModelUsage {
+            input_tokens: 400,
+            output_tokens: 100,
+            total_tokens: 500,
+        });
+
+        let exceeded = limiter.reserve(&subjects(), 0).unwrap_err();
+        assert_eq!(exceeded.kind, LimitKind::OutputTokens);
+
+        let usage = limiter.usage.lock().unwrap();
+        assert_eq!(usage.budgets.entries[&subjects().subjects[0]].day.used, 500);
+    }
+
+    #[test]
+    fn settles_requests_cut_short_on_their_output() {
+        let limiter = limiter(Quota {
+            tokens_per_day: Some(1_000),
+            ..Default::default()
+        });
+
+        let reservation = limiter.reserve(&subjects(), 300).unwrap();
+        reservation.settle_partial(None, 200);
+        let reservation = limiter.reserve(&subjects(), 100).unwrap();
+        reservation.settle_partial(
+            Some(&AILanguageModelUsage {
+                input_tokens: 100,
+                output_tokens: 0,
+                total_tokens: 100,
+            }),
+            50,
+        );
+
+        let usage = limiter.usage.lock().unwrap();
+        assert_eq!(usage.budgets.entries[&subjects().subjects[0]].day.used, 650);
+    }
+
+    #[test]
+    fn enforces_budgets() {
+        let limiter = limiter(Quota {
+            tokens_per_day: Some(1_000),
+            ..Default::default()
+        });
+
+        assert!(limiter.reserve(&subjects(), 800).is_ok());
+        let exceeded = limiter.reserve(&subjects(), 300).unwrap_err();
+        assert!(exceeded.kind.is_budget());
+        assert_eq!(exceeded.status.remaining, 200);
+    }
+
+    #[test]
+    fn reports_the_tightest_limit() {
+        let limiter = limiter(Quota {
+            requests_per_minute: Some(10),
+            input_tokens_per_minute: Some(1_000),
+            ..Default::default()
+        });
+
+        let reservation = limiter.reserve(&subjects(), 900).unwrap();
+        let status = reservation.status().unwrap();
+        assert_eq!(status.limit, 1_000);
+        assert_eq!(status.remaining, 100);
+    }
+
+    #[test]
+    fn applies_the_limits_of_the_api_key() {
+        let limiter = limiter(Quota::default());
+        let key = ApiKey {
+            id: "key".to_string(),
+            name: "Backend".to_string(),
+            endpoints: vec![],
+            models: vec![],
+            limits: Some(Quota {
+                requests_per_minute: Some(1),
+                ..Default::default()
+            }),
+            guardrails: Default::default(),
+            created_at: 0,
+            expires_at: None,
+        };
+        let subjects = Subjects {
+            subjects: vec![Subject::ApiKey(key.id.clone())],
+            api_key: Some(Arc::new(key)),
+            client_token: None,
+            origin_policy: None,
+            guardrails: Default::default(),
+            allowance: None,
+        };
+
+        assert!(limiter.reserve(&subjects, 0).is_ok());
+        assert!(limiter.reserve(&subjects, 0).is_err());
+    }
+
+    #[test]
+    fn converts_calendar_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(
+            civil_from_days(days_from_civil(2025, 12, 31)),
+            (2025, 12, 31)
+        );
+    }
+}
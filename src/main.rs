@@ -1,23 +1,69 @@
 pub mod ai;
+mod api_keys;
+mod client_tokens;
+mod generations;
+mod ledger;
+mod limits;
 mod middleware;
+mod origins;
+mod proof_of_work;
 mod routes;
+mod upstream;
 
-use std::{collections::HashSet, env, error::Error, sync::Arc};
+use std::{env, error::Error, fs, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
 
+use api_keys::ApiKeys;
 use axum::{
     Router,
-    http::{HeaderValue, Method},
-    middleware::from_fn_with_state,
+    http::{Extensions, HeaderMap, Method, StatusCode, Version, header},
+    middleware::{from_fn, from_fn_with_state},
 };
+use built_in_hybrid_server::ai::language_model::{AILanguageModelSafetySettings, RetryPolicy};
+use client_tokens::TokenSigner;
 use gcp_auth::TokenProvider;
 use gemini_rs::prelude::GeminiClient;
-use middleware::allowed_origins::allowed_origins_middelware;
-use tower_http::{compression::CompressionLayer, cors::CorsLayer, services::ServeDir};
+use generations::Generations;
+use ledger::Ledger;
+use limits::{Limiter, LimitsConfig, RATELIMIT_HEADERS};
+use middleware::{
+    allowed_origins::allowed_origins_middelware,
+    api_key::api_key_middleware,
+    client_token::{CLIENT_TOKEN_HEADER, client_token_middleware},
+    request_id::{REQUEST_ID_HEADER, request_id_middleware},
+};
+use origins::{OriginPolicies, OriginPolicy};
+use proof_of_work::{PowConfig, ProofOfWork};
+use tower_http::{
+    compression::{CompressionLayer, DefaultPredicate, Predicate},
+    cors::{AllowOrigin, CorsLayer},
+    services::ServeDir,
+};
+use upstream::{BreakerConfig, QueueConfig, Upstream};
 
 #[derive(Clone)]
 pub struct AppState {
     pub gemini_client: GeminiClient<Arc<dyn TokenProvider>>,
-    pub accepted_origins: Arc<HashSet<HeaderValue>>,
+    pub origins: Arc<OriginPolicies>,
+    pub generations: Arc<Generations>,
+    pub retry_policy: RetryPolicy,
+    /// How long a request may take, including retries. Clients can ask for less.
+    pub request_timeout: Duration,
+    pub upstream: Arc<Upstream>,
+    pub limiter: Arc<Limiter>,
+    /// How many proxies that append to `X-Forwarded-For` the server runs behind.
+    pub trusted_proxies: usize,
+    pub ledger: Ledger,
+    pub api_keys: Arc<ApiKeys>,
+    /// Signs client tokens, which are disabled without a signing key.
+    pub token_signer: Option<Arc<TokenSigner>>,
+    /// Whether requests from browsers need a client token, rather than only an allowed origin.
+    pub require_client_token: bool,
+    /// Gates the anonymous prompts from some origins behind proof-of-work challenges.
+    pub proof_of_work: Arc<ProofOfWork>,
+    /// The bearer token of the admin endpoints, which are disabled without one.
+    pub admin_token: Option<Arc<str>>,
+    /// The safety settings of the categories that origins and API keys leave out.
+    pub safety_settings: Arc<AILanguageModelSafetySettings>,
 }
 
 #[tokio::main]
@@ -28,10 +74,87 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let project_id = env::var("PROJECT_ID")?;
     let location_id = env::var("LOCATION_ID")?;
     let bind_address = env::var("BIND_ADDRESS").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
-    let accepted_origins = env::var("ALLOWED_ORIGINS")?
+    // The policies of the configuration file come first, then the origins allowed with the
+    // default settings.
+    let mut origin_policies: Vec<OriginPolicy> = match env::var("ORIGINS_CONFIG") {
+        Ok(path) => serde_json::from_str(&fs::read_to_string(path)?)?,
+        Err(_) => vec![],
+    };
+    for pattern in env::var("ALLOWED_ORIGINS")
+        .unwrap_or_default()
         .split(";")
-        .filter_map(|header| HeaderValue::from_str(header).ok())
-        .collect::<Vec<_>>();
+        .filter(|pattern| !pattern.is_empty())
+    {
+        origin_policies.push(OriginPolicy::allow(pattern.parse()?));
+    }
+    let origins = Arc::new(OriginPolicies::new(origin_policies));
+    let request_timeout = Duration::from_secs(env_or("REQUEST_TIMEOUT_SECS", 60));
+    let retry_policy = RetryPolicy {
+        max_attempts: env_or("RETRY_MAX_ATTEMPTS", RetryPolicy::default().max_attempts),
+        ..RetryPolicy::default()
+    };
+    let queue_config = QueueConfig {
+        max_concurrency: env_or(
+            "MAX_CONCURRENT_REQUESTS",
+            QueueConfig::default().max_concurrency,
+        ),
+        max_queued: env_or("MAX_QUEUED_REQUESTS", QueueConfig::default().max_queued),
+        max_queued_per_client: env_or(
+            "MAX_QUEUED_REQUESTS_PER_CLIENT",
+            QueueConfig::default().max_queued_per_client,
+        ),
+        max_wait: Duration::from_secs(env_or(
+            "MAX_QUEUE_WAIT_SECS",
+            QueueConfig::default().max_wait.as_secs(),
+        )),
+    };
+
+    let limits_config: LimitsConfig = match env::var("LIMITS_CONFIG") {
+        Ok(path) => serde_json::from_str(&fs::read_to_string(path)?)?,
+        Err(_) => LimitsConfig::default(),
+    };
+    // `true` stands for a single proxy, the usual setup.
+    let trusted_proxies = match env::var("TRUST_PROXY").as_deref() {
+        Ok("true") => 1,
+        Ok("") | Err(_) => 0,
+        Ok(proxies) => parse_setting("TRUST_PROXY", proxies)?,
+    };
+    let ledger_path = env::var("LEDGER_PATH").unwrap_or_else(|_| "ledger.db".to_string());
+    let ledger = Ledger::open(&ledger_path)?;
+    let api_keys = ApiKeys::open(&ledger_path)?;
+    let token_signer = env::var("TOKEN_SIGNING_KEY")
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(|key| Arc::new(TokenSigner::new(key.as_bytes())));
+    let require_client_token = match env::var("REQUIRE_CLIENT_TOKEN").as_deref() {
+        Ok("") | Err(_) => false,
+        Ok(required) => parse_setting("REQUIRE_CLIENT_TOKEN", required)?,
+    };
+    let pow_config = PowConfig {
+        origins: env::var("POW_ORIGINS")
+            .unwrap_or_default()
+            .split(";")
+            .filter(|origin| !origin.is_empty())
+            .map(str::to_string)
+            .collect(),
+        base_difficulty: env_or("POW_BASE_DIFFICULTY", PowConfig::default().base_difficulty),
+        max_difficulty: env_or("POW_MAX_DIFFICULTY", PowConfig::default().max_difficulty),
+        allowance_prompts: env_or(
+            "POW_ALLOWANCE_PROMPTS",
+            PowConfig::default().allowance_prompts,
+        ),
+        ..PowConfig::default()
+    };
+    let admin_token = env::var("ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+        .map(Arc::from);
+
+    // A JSON object of thresholds by harm category, e.g. {"HARM_CATEGORY_HARASSMENT": "BLOCK_ONLY_HIGH"}.
+    let safety_settings: AILanguageModelSafetySettings = match env::var("SAFETY_SETTINGS") {
+        Ok(settings) => serde_json::from_str(&settings)?,
+        Err(_) => AILanguageModelSafetySettings::default(),
+    };
 
     let authentication_manager = gcp_auth::provider().await?;
     tracing::info!("GCP AuthenticationManager initialized.");
@@ -46,37 +169,105 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let app_state = AppState {
         gemini_client,
-        accepted_origins: Arc::new(HashSet::from_iter(accepted_origins.clone().into_iter())),
+        origins: origins.clone(),
+        generations: Arc::new(Generations::default()),
+        retry_policy,
+        request_timeout,
+        upstream: Arc::new(Upstream::new(BreakerConfig::default(), queue_config)),
+        limiter: Arc::new(Limiter::new(limits_config)),
+        trusted_proxies,
+        ledger,
+        api_keys: Arc::new(api_keys),
+        token_signer,
+        require_client_token,
+        proof_of_work: Arc::new(ProofOfWork::new(pow_config)),
+        admin_token,
+        safety_settings: Arc::new(safety_settings),
     };
 
-    // Sets up a compression layer that supports brotli, deflate, gzip, and zstd.
+    // Sets up a compression layer that supports brotli, deflate, gzip, and zstd. Streamed responses
+    // are skipped, as the encoder would hold chunks back until it fills a block.
     let compression_layer = CompressionLayer::new()
         .br(true)
         .deflate(true)
         .gzip(true)
-        .zstd(true);
+        .zstd(true)
+        .compress_when(DefaultPredicate::new().and(
+            |_: StatusCode, _: Version, _: &HeaderMap, extensions: &Extensions| {
+                extensions.get::<routes::Streamed>().is_none()
+            },
+        ));
 
     let cors = CorsLayer::new()
         // allow `GET` and `POST` when accessing the resource
         .allow_methods([Method::GET, Method::POST])
-        // allow requests from any origin
-        .allow_origin(accepted_origins);
+        // allow requests from the origins with a policy
+        .allow_origin(AllowOrigin::predicate(move |origin, _| {
+            origin
+                .to_str()
+                .is_ok_and(|origin| origins.find(origin).is_some())
+        }))
+        // let pages send JSON, their client token, their proof-of-work allowance and the time
+        // they are willing to wait
+        .allow_headers([
+            header::CONTENT_TYPE,
+            CLIENT_TOKEN_HEADER,
+            routes::ALLOWANCE_HEADER,
+            routes::REQUEST_TIMEOUT_HEADER,
+        ])
+        // let clients read the id they need to cancel a generation, the id of the request, and
+        // their rate limits
+        .expose_headers(
+            [routes::GENERATION_ID_HEADER, REQUEST_ID_HEADER]
+                .into_iter()
+                .chain(RATELIMIT_HEADERS)
+                .collect::<Vec<_>>(),
+        );
 
-    // Create Router.
+    // Create Router. CORS is the outermost layer, so preflight requests, which carry neither a
+    // client token nor an API key, are answered before the checks that would refuse them, and the
+    // errors of those checks can be read by pages.
     let app = Router::new()
         .fallback_service(ServeDir::new("static"))
         .merge(routes::routes())
-        .layer(cors)
         .layer(compression_layer)
         .layer(from_fn_with_state(
             app_state.clone(),
             allowed_origins_middelware,
         ))
+        .layer(from_fn_with_state(
+            app_state.clone(),
+            client_token_middleware,
+        ))
+        .layer(from_fn_with_state(app_state.clone(), api_key_middleware))
+        .layer(from_fn(request_id_middleware))
+        .layer(cors)
         .with_state(app_state);
 
     let listener = tokio::net::TcpListener::bind(bind_address).await?;
     tracing::info!("listening on {}", listener.local_addr()?);
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
+
+// Reads an optional tuning setting from the environment, falling back to `default` when it is
+// unset or invalid.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// Parses a setting that decides who is trusted. A typo fails startup, rather than falling back to
+// a default that quietly opens the server, or blocks every client.
+fn parse_setting<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {}: {:?}", name, value))
+}
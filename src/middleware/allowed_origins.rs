@@ -1,29 +1,68 @@
+use std::sync::Arc;
+
 use axum::{
     extract::{Request, State},
-    http::StatusCode,
+    http::header,
     middleware::Next,
     response::{IntoResponse, Response},
 };
 use tracing::info;
 
-use crate::AppState;
+use crate::{
+    AppState, api_keys::ApiKey, client_tokens::ClientToken, origins::OriginPolicy,
+    routes::ApplicationError,
+};
 
+/// Applies the policy of the request's origin to the language model routes. Requests from origins
+/// without a policy are refused, unless they carry an API key or a client token. The policy is
+/// added to the request's extensions, as an `Arc<OriginPolicy>`, for the handlers.
 pub async fn allowed_origins_middelware(
     State(app_state): State<AppState>,
-    req: Request,
+    mut req: Request,
     next: Next,
 ) -> Response {
-    if !req.uri().path().starts_with("/language-model") {
+    let Some(path) = req.uri().path().strip_prefix("/language-model") else {
+        return next.run(req).await;
+    };
+
+    // Requests with an API key don't come from browsers, and were authenticated by the key.
+    if req.extensions().get::<Arc<ApiKey>>().is_some() {
         return next.run(req).await;
     }
 
-    let origin = req.headers().get("origin");
-    if let Some(origin) = origin {
-        if app_state.accepted_origins.contains(origin) {
-            return next.run(req).await;
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok());
+    let policy = origin
+        .and_then(|origin| app_state.origins.find(origin))
+        .cloned();
+    if let Some(policy) = &policy
+        && !policy.allows_endpoint(path)
+    {
+        info!(origin = ?origin, path = req.uri().path(), "Origin not allowed for endpoint.");
+        return ApplicationError::Forbidden("The origin may not call this endpoint.")
+            .into_response();
+    }
+
+    // Requests with a client token were checked against the origin the token is bound to.
+    if req.extensions().get::<Arc<ClientToken>>().is_none() {
+        if app_state.require_client_token {
+            info!(
+                path = req.uri().path(),
+                "Request without a client token or API key."
+            );
+            return ApplicationError::Unauthorized("A client token or API key is required.")
+                .into_response();
+        }
+        if policy.is_none() {
+            info!(origin = ?origin, path = req.uri().path(), "Forbidden origin for request.");
+            return ApplicationError::ForbiddenOrigin.into_response();
         }
     }
 
-    info!(origin = ?origin, uri = ?req.uri(), "Forbidden origin for request.");
-    (StatusCode::FORBIDDEN, "Forbidden").into_response()
+    if let Some(policy) = policy {
+        req.extensions_mut().insert::<Arc<OriginPolicy>>(policy);
+    }
+    next.run(req).await
 }
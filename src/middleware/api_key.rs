@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::info;
+
+use crate::{AppState, api_keys::ApiKey, routes::ApplicationError};
+
+pub const API_KEY_HEADER: HeaderName = HeaderName::from_static("x-api-key");
+
+/// Authenticates the requests to the language model routes that carry an API key, in the
+/// `X-Api-Key` header or as a bearer token, and checks the key may call the endpoint. The key is
+/// added to the request's extensions, as an `Arc<ApiKey>`, for the origin check and the handlers.
+pub async fn api_key_middleware(
+    State(app_state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let Some(path) = req.uri().path().strip_prefix("/language-model") else {
+        return next.run(req).await;
+    };
+    let Some(secret) = api_key(req.headers()) else {
+        return next.run(req).await;
+    };
+
+    let Some(key) = app_state.api_keys.authenticate(secret) else {
+        info!(path = req.uri().path(), "Invalid API key for request.");
+        return ApplicationError::InvalidApiKey.into_response();
+    };
+    if !key.allows_endpoint(path) {
+        info!(key = %key.id, path = req.uri().path(), "API key not allowed for endpoint.");
+        return ApplicationError::Forbidden("The API key may not call this endpoint.")
+            .into_response();
+    }
+
+    req.extensions_mut().insert::<Arc<ApiKey>>(key);
+    next.run(req).await
+}
+
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+        })
+}
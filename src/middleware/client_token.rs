@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::info;
+
+use crate::{
+    AppState,
+    client_tokens::{ClientToken, TokenError},
+    ledger::unix_now,
+    routes::ApplicationError,
+};
+
+pub const CLIENT_TOKEN_HEADER: HeaderName = HeaderName::from_static("x-client-token");
+
+/// Verifies the client tokens presented to the language model routes, in the `X-Client-Token`
+/// header or, where browsers can't set headers, in the `token` query parameter. A token has to be
+/// signed by the server, unexpired, issued by a key that still works, presented from the origin it
+/// is bound to, and allow the endpoint. It is added to the request's extensions, as an `Arc<ClientToken>`.
+pub async fn client_token_middleware(
+    State(app_state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let Some(path) = req.uri().path().strip_prefix("/language-model") else {
+        return next.run(req).await;
+    };
+    let Some(token) = client_token(&req) else {
+        return next.run(req).await;
+    };
+
+    // Without a signing key, no token can be valid.
+    let verified = match &app_state.token_signer {
+        Some(signer) => signer.verify(token, unix_now()),
+        None => Err(TokenError::InvalidSignature),
+    }
+    .and_then(|token| match app_state.api_keys.get(&token.key) {
+        Some(_) => Ok(token),
+        None => Err(TokenError::KeyRevoked),
+    });
+    let token = match verified {
+        Ok(token) => token,
+        Err(e) => {
+            info!(
+                path = req.uri().path(),
+                "Invalid client token for request: {}", e
+            );
+            return ApplicationError::InvalidClientToken(e).into_response();
+        }
+    };
+
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok());
+    if origin != Some(token.origin.as_str()) {
+        info!(token = %token.id, origin = ?origin, "Client token used from another origin.");
+        return ApplicationError::Forbidden("The client token is bound to another origin.")
+            .into_response();
+    }
+    if !token.allows_endpoint(path) {
+        info!(token = %token.id, path = req.uri().path(), "Client token not allowed for endpoint.");
+        return ApplicationError::Forbidden("The client token may not call this endpoint.")
+            .into_response();
+    }
+
+    req.extensions_mut()
+        .insert::<Arc<ClientToken>>(Arc::new(token));
+    next.run(req).await
+}
+
+fn client_token(req: &Request) -> Option<&str> {
+    req.headers()
+        .get(CLIENT_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| {
+            req.uri()
+                .query()?
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("token="))
+        })
+}
@@ -1 +1,4 @@
 pub mod allowed_origins;
+pub mod api_key;
+pub mod client_token;
+pub mod request_id;
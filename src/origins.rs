@@ -0,0 +1,318 @@
+use std::{
+    collections::BTreeMap, error::Error, fmt::Display, ops::RangeInclusive, str::FromStr, sync::Arc,
+};
+
+use serde::{Deserialize, de::IgnoredAny};
+
+use crate::{
+    api_keys::{allows_endpoint, allows_model},
+    limits::Quota,
+};
+use built_in_hybrid_server::ai::language_model::AILanguageModelGuardrails;
+
+const LOOPBACK_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "[::1]"];
+
+/// The origins a policy applies to:
+///
+/// - an exact origin, `https://example.com`;
+/// - the subdomains of a domain, at any depth, `https://*.example.com`;
+/// - a range of ports on a loopback host, for development, `http://localhost:3000-3999`, or any
+///   port, `http://localhost:*`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum OriginPattern {
+    Exact(String),
+    Subdomains {
+        scheme: String,
+        /// The domain, with its port if any, that the subdomains end with.
+        domain: String,
+    },
+    Ports {
+        scheme: String,
+        host: String,
+        ports: RangeInclusive<u16>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidOriginPattern(String);
+
+impl Display for InvalidOriginPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid origin pattern: {}", self.0)
+    }
+}
+
+impl Error for InvalidOriginPattern {}
+
+impl FromStr for OriginPattern {
+    type Err = InvalidOriginPattern;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidOriginPattern(pattern.to_string());
+        let pattern = pattern.trim().to_ascii_lowercase();
+        let (scheme, authority) = pattern.split_once("://").ok_or_else(invalid)?;
+        if scheme.is_empty() || authority.is_empty() || authority.contains('/') {
+            return Err(invalid());
+        }
+
+        if let Some(domain) = authority.strip_prefix("*.") {
+            if domain.is_empty() || domain.contains('*') {
+                return Err(invalid());
+            }
+            return Ok(OriginPattern::Subdomains {
+                scheme: scheme.to_string(),
+                domain: domain.to_string(),
+            });
+        }
+
+        match authority.rsplit_once(':') {
+            Some((host, ports)) if ports == "*" || ports.contains('-') => {
+                if !LOOPBACK_HOSTS.contains(&host) {
+                    return Err(invalid());
+                }
+                let ports = match ports.split_once('-') {
+                    Some((start, end)) => {
+                        let start = start.parse().map_err(|_| invalid())?;
+                        let end = end.parse().map_err(|_| invalid())?;
+                        if start > end {
+                            return Err(invalid());
+                        }
+                        start..=end
+                    }
+                    None => 1..=u16::MAX,
+                };
+                Ok(OriginPattern::Ports {
+                    scheme: scheme.to_string(),
+                    host: host.to_string(),
+                    ports,
+                })
+            }
+            _ if authority.contains('*') => Err(invalid()),
+            _ => Ok(OriginPattern::Exact(pattern)),
+        }
+    }
+}
+
+impl TryFrom<String> for OriginPattern {
+    type Error = InvalidOriginPattern;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        pattern.parse()
+    }
+}
+
+impl OriginPattern {
+    pub fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        match self {
+            OriginPattern::Exact(exact) => origin == *exact,
+            OriginPattern::Subdomains { scheme, domain } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|rest| rest.strip_prefix("://"))
+                .and_then(|authority| authority.strip_suffix(domain.as_str()))
+                .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+            OriginPattern::Ports {
+                scheme,
+                host,
+                ports,
+            } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|rest| rest.strip_prefix("://"))
+                .and_then(|authority| authority.strip_prefix(host.as_str()))
+                .and_then(|port| port.strip_prefix(':'))
+                .and_then(|port| port.parse::<u16>().ok())
+                .is_some_and(|port| ports.contains(&port)),
+        }
+    }
+}
+
+/// The settings of the origins matching a pattern.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", try_from = "RawOriginPolicy")]
+pub struct OriginPolicy {
+    pub origin: OriginPattern,
+    /// The endpoints the origin may call, as paths below `/language-model`. Empty allows them all.
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    /// The models the origin may use. Empty allows every model.
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// The limits of the origin, instead of the default origin limits.
+    pub limits: Option<Quota>,
+    /// The guardrails of the origin's requests.
+    #[serde(flatten)]
+    pub guardrails: AILanguageModelGuardrails,
+}
+
+// A policy as written in the configuration. The guardrails are flattened into it, which
+// `deny_unknown_fields` doesn't support, so the fields left over are collected and refused instead.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawOriginPolicy {
+    origin: OriginPattern,
+    #[serde(default)]
+    endpoints: Vec<String>,
+    #[serde(default)]
+    models: Vec<String>,
+    limits: Option<Quota>,
+    #[serde(flatten)]
+    guardrails: AILanguageModelGuardrails,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, IgnoredAny>,
+}
+
+impl TryFrom<RawOriginPolicy> for OriginPolicy {
+    type Error = String;
+
+    fn try_from(policy: RawOriginPolicy) -> Result<Self, Self::Error> {
+        if let Some(field) = policy.unknown.keys().next() {
+            return Err(format!("unknown field `{}`", field));
+        }
+        Ok(OriginPolicy {
+            origin: policy.origin,
+            endpoints: policy.endpoints,
+            models: policy.models,
+            limits: policy.limits,
+            guardrails: policy.guardrails,
+        })
+    }
+}
+
+impl OriginPolicy {
+    /// A policy allowing the origins of `pattern` without any settings of their own.
+    pub fn allow(pattern: OriginPattern) -> Self {
+        OriginPolicy {
+            origin: pattern,
+            endpoints: vec![],
+            models: vec![],
+            limits: None,
+            guardrails: AILanguageModelGuardrails::default(),
+        }
+    }
+
+    pub fn allows_endpoint(&self, path: &str) -> bool {
+        allows_endpoint(&self.endpoints, path)
+    }
+
+    pub fn allows_model(&self, model: &str) -> bool {
+        allows_model(&self.models, model)
+    }
+}
+
+/// The allowed origins and their settings. An origin follows the first policy that matches it.
+#[derive(Debug, Clone, Default)]
+pub struct OriginPolicies {
+    policies: Vec<Arc<OriginPolicy>>,
+}
+
+impl OriginPolicies {
+    pub fn new(policies: Vec<OriginPolicy>) -> Self {
+        OriginPolicies {
+            policies: policies.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    pub fn find(&self, origin: &str) -> Option<&Arc<OriginPolicy>> {
+        self.policies
+            .iter()
+            .find(|policy| policy.origin.matches(origin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(pattern: &str) -> OriginPattern {
+        pattern.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_origin_patterns() {
+        let exact = pattern("https://example.com");
+        assert!(exact.matches("https://example.com"));
+        assert!(exact.matches("https://EXAMPLE.com"));
+        assert!(!exact.matches("http://example.com"));
+
+        let subdomains = pattern("https://*.example.com");
+        assert!(subdomains.matches("https://app.example.com"));
+        assert!(subdomains.matches("https://a.b.example.com"));
+        assert!(!subdomains.matches("https://example.com"));
+        assert!(!subdomains.matches("https://badexample.com"));
+        assert!(!subdomains.matches("https://app.example.com:8443"));
+
+        let ports = pattern("http://localhost:3000-3999");
+        assert!(ports.matches("http://localhost:3000"));
+        assert!(ports.matches("http://localhost:3999"));
+        assert!(!ports.matches("http://localhost:4000"));
+        assert!(!ports.matches("http://localhost"));
+        assert!(!ports.matches("http://localhost.example.com:3000"));
+    }
+
+    #[test]
+    fn refuses_invalid_patterns() {
+        for invalid in [
+            "example.com",
+            "https://*",
+            "https://app.*.com",
+            "https://example.com:3000-3999",
+            "http://localhost:3999-3000",
+            "https://example.com/path",
+        ] {
+            assert!(invalid.parse::<OriginPattern>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn follows_the_first_matching_policy() {
+        let policies = OriginPolicies::new(vec![
+            OriginPolicy {
+                endpoints: vec!["/prompt".to_string()],
+                ..OriginPolicy::allow(pattern("https://admin.example.com"))
+            },
+            OriginPolicy::allow(pattern("https://*.example.com")),
+        ]);
+
+        assert_eq!(
+            policies
+                .find("https://admin.example.com")
+                .unwrap()
+                .endpoints,
+            vec!["/prompt".to_string()]
+        );
+        assert!(
+            policies
+                .find("https://app.example.com")
+                .unwrap()
+                .endpoints
+                .is_empty()
+        );
+        assert!(policies.find("https://example.org").is_none());
+    }
+
+    #[test]
+    fn reads_guardrails_from_policies() {
+        let policy: OriginPolicy = serde_json::from_str(
+            r#"{"origin":"https://example.com","maxTemperature":0.5,"modalities":["text"],
+            "systemPrompt":"Only answer questions about cooking."}"#,
+        )
+        .unwrap();
+
+        assert_eq!(policy.guardrails.max_temperature, Some(0.5));
+        assert_eq!(
+            policy.guardrails.system_prompt.as_deref(),
+            Some("Only answer questions about cooking.")
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_fields_in_policies() {
+        let error = serde_json::from_str::<OriginPolicy>(
+            r#"{"origin":"https://example.com","maxTemprature":0.5}"#,
+        )
+        .unwrap_err();
+
+        assert!(error.to_string().contains("maxTemprature"));
+    }
+}
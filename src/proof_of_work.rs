@@ -0,0 +1,453 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::ledger::unix_now;
+
+// How long it takes an abuse score to halve.
+const ABUSE_HALF_LIFE: Duration = Duration::from_secs(10 * 60);
+// Past this many allowances or abuse scores, the expired ones are dropped.
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct PowConfig {
+    /// The origins whose anonymous requests need an allowance, `*` standing for every origin.
+    pub origins: HashSet<String>,
+    /// The difficulty of a challenge, in leading zero bits, when the providers are idle and the
+    /// client has not misbehaved.
+    pub base_difficulty: u32,
+    pub max_difficulty: u32,
+    /// How many prompts solving a challenge allows.
+    pub allowance_prompts: u32,
+    pub allowance_ttl: Duration,
+    pub challenge_ttl: Duration,
+}
+
+impl Default for PowConfig {
+    fn default() -> Self {
+        PowConfig {
+            origins: HashSet::new(),
+            base_difficulty: 14,
+            max_difficulty: 22,
+            allowance_prompts: 20,
+            allowance_ttl: Duration::from_secs(10 * 60),
+            challenge_ttl: Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+/// Makes anonymous clients pay for their prompts with computation. A client asks for a challenge,
+/// finds a solution whose SHA-256 hash of `<challenge>:<solution>` starts with `difficulty` zero
+/// bits, and trades it for an allowance of prompts.
+///
+/// Challenges get harder as the providers get busier, and for the IP addresses that misbehave:
+/// those that ask for many challenges, send invalid solutions, or go over their rate limits.
+///
+/// Issuing a challenge stores nothing: a challenge is `<nonce>.<difficulty>.<expiry>.<signature>`,
+/// signed with HMAC-SHA256 by a key generated at startup. Only the nonces of solved challenges are
+/// kept, until they expire, so each challenge is solved once.
+#[derive(Debug)]
+pub struct ProofOfWork {
+    config: PowConfig,
+    mac: Hmac<Sha256>,
+    solved: Mutex<Solved>,
+    allowances: Mutex<HashMap<String, Allowance>>,
+    abuse: Mutex<HashMap<IpAddr, AbuseScore>>,
+}
+
+// The nonces of the solved challenges that have not expired yet, with their expiries in the order
+// they were solved.
+#[derive(Debug, Default)]
+struct Solved {
+    nonces: HashSet<String>,
+    expiries: VecDeque<(i64, String)>,
+}
+
+impl Solved {
+    // Records a solved challenge. Returns `false` if it was solved before.
+    fn insert(&mut self, nonce: &str, expires_at: i64, now: i64) -> bool {
+        // Challenges all last as long, so those solved first mostly expire first.
+        while self
+            .expiries
+            .front()
+            .is_some_and(|(expires_at, _)| *expires_at <= now)
+        {
+            let (_, nonce) = self.expiries.pop_front().unwrap();
+            self.nonces.remove(&nonce);
+        }
+        if !self.nonces.insert(nonce.to_string()) {
+            return false;
+        }
+        self.expiries.push_back((expires_at, nonce.to_string()));
+        true
+    }
+}
+
+#[derive(Debug)]
+struct Allowance {
+    remaining: u32,
+    expires: Instant,
+}
+
+#[derive(Debug)]
+struct AbuseScore {
+    score: f64,
+    updated: Instant,
+}
+
+impl AbuseScore {
+    fn decayed(&self, now: Instant) -> f64 {
+        let half_lives =
+            now.duration_since(self.updated).as_secs_f64() / ABUSE_HALF_LIFE.as_secs_f64();
+        self.score * 0.5f64.powf(half_lives)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IssuedChallenge {
+    pub challenge: String,
+    pub difficulty: u32,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IssuedAllowance {
+    pub allowance: String,
+    pub prompts: u32,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowError {
+    /// The request has no allowance, or its allowance is used up or expired.
+    ChallengeRequired,
+    UnknownChallenge,
+    InvalidSolution,
+}
+
+impl Display for PowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PowError::ChallengeRequired => {
+                write!(
+                    f,
+                    "Solve a proof-of-work challenge to get an allowance of prompts."
+                )
+            }
+            PowError::UnknownChallenge => write!(f, "The challenge is unknown or expired."),
+            PowError::InvalidSolution => write!(f, "The challenge's solution is invalid."),
+        }
+    }
+}
+
+impl ProofOfWork {
+    pub fn new(config: PowConfig) -> Self {
+        ProofOfWork {
+            config,
+            mac: Hmac::new_from_slice(&rand::random::<[u8; 32]>())
+                .expect("HMAC takes keys of any length"),
+            solved: Mutex::default(),
+            allowances: Mutex::default(),
+            abuse: Mutex::default(),
+        }
+    }
+
+    /// Whether the anonymous requests from `origin` need an allowance.
+    pub fn applies_to(&self, origin: Option<&str>) -> bool {
+        self.config.origins.contains("*")
+            || origin.is_some_and(|origin| self.config.origins.contains(origin))
+    }
+
+    /// Issues a challenge to the client at `ip`, while the providers are under `load`.
+    pub fn issue(&self, ip: Option<IpAddr>, load: f64) -> IssuedChallenge {
+        let difficulty = self.difficulty(ip, load, Instant::now());
+        // Asking for challenges is cheap, so asking for many makes the next ones harder.
+        if let Some(ip) = ip {
+            self.report_abuse(ip, 0.25);
+        }
+
+        let expires_at = unix_now() + self.config.challenge_ttl.as_secs() as i64;
+        let payload = format!("{}.{}.{}", random_id(), difficulty, expires_at);
+        let mut mac = self.mac.clone();
+        mac.update(payload.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+        IssuedChallenge {
+            challenge: format!("{}.{}", payload, signature),
+            difficulty,
+            expires_at,
+        }
+    }
+
+    /// Trades the solution of a challenge for an allowance. Each challenge can only be solved once.
+    pub fn solve(
+        &self,
+        challenge: &str,
+        solution: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<IssuedAllowance, PowError> {
+        self.solve_at(challenge, solution, ip, unix_now())
+    }
+
+    // Solves a challenge at `now` seconds since the Unix epoch.
+    fn solve_at(
+        &self,
+        challenge: &str,
+        solution: &str,
+        ip: Option<IpAddr>,
+        now: i64,
+    ) -> Result<IssuedAllowance, PowError> {
+        let (nonce, difficulty, expires_at) = self
+            .verify(challenge)
+            .filter(|(_, _, expires_at)| *expires_at > now)
+            .ok_or(PowError::UnknownChallenge)?;
+
+        let hash = Sha256::digest(format!("{}:{}", challenge, solution).as_bytes());
+        if leading_zero_bits(&hash) < difficulty {
+            if let Some(ip) = ip {
+                self.report_abuse(ip, 1.0);
+            }
+            return Err(PowError::InvalidSolution);
+        }
+        if !self.solved.lock().unwrap().insert(nonce, expires_at, now) {
+            return Err(PowError::UnknownChallenge);
+        }
+
+        let allowance = random_id();
+        let instant = Instant::now();
+        let mut allowances = self.allowances.lock().unwrap();
+        if allowances.len() > MAX_ENTRIES {
+            allowances.retain(|_, allowance| allowance.expires > instant);
+        }
+        allowances.insert(
+            allowance.clone(),
+            Allowance {
+                remaining: self.config.allowance_prompts,
+                expires: instant + self.config.allowance_ttl,
+            },
+        );
+
+        Ok(IssuedAllowance {
+            allowance,
+            prompts: self.config.allowance_prompts,
+            expires_at: now + self.config.allowance_ttl.as_secs() as i64,
+        })
+    }
+
+    // Checks the signature of a challenge, and returns its nonce, difficulty and expiry.
+    fn verify<'a>(&self, challenge: &'a str) -> Option<(&'a str, u32, i64)> {
+        let (payload, signature) = challenge.rsplit_once('.')?;
+        let mut mac = self.mac.clone();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&URL_SAFE_NO_PAD.decode(signature).ok()?)
+            .ok()?;
+
+        let mut parts = payload.split('.');
+        let (Some(nonce), Some(difficulty), Some(expires_at), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return None;
+        };
+        Some((nonce, difficulty.parse().ok()?, expires_at.parse().ok()?))
+    }
+
+    /// Spends a prompt from an allowance.
+    pub fn spend(&self, allowance: Option<&str>) -> Result<(), PowError> {
+        self.with_allowance(allowance, |entry| entry.remaining -= 1)
+    }
+
+    /// Checks that an allowance has a prompt left, without spending it.
+    pub fn check(&self, allowance: Option<&str>) -> Result<(), PowError> {
+        self.with_allowance(allowance, |_| {})
+    }
+
+    // Runs `f` on an allowance that has a prompt left, dropping it once it is used up or expired.
+    fn with_allowance(
+        &self,
+        allowance: Option<&str>,
+        f: impl FnOnce(&mut Allowance),
+    ) -> Result<(), PowError> {
+        let now = Instant::now();
+        let mut allowances = self.allowances.lock().unwrap();
+        let Some(entry) = allowance.and_then(|allowance| allowances.get_mut(allowance)) else {
+            return Err(PowError::ChallengeRequired);
+        };
+        if entry.expires <= now || entry.remaining == 0 {
+            allowances.remove(allowance.unwrap_or_default());
+            return Err(PowError::ChallengeRequired);
+        }
+        f(entry);
+        Ok(())
+    }
+
+    /// Makes the next challenges of `ip` harder, by `amount` bits until the score decays.
+    pub fn report_abuse(&self, ip: IpAddr, amount: f64) {
+        let now = Instant::now();
+        let mut abuse = self.abuse.lock().unwrap();
+        if abuse.len() > MAX_ENTRIES {
+            abuse.retain(|_, score| score.decayed(now) >= 0.5);
+        }
+        let score = abuse.entry(ip).or_insert(AbuseScore {
+            score: 0.0,
+            updated: now,
+        });
+        *score = AbuseScore {
+            score: score.decayed(now) + amount,
+            updated: now,
+        };
+    }
+
+    fn difficulty(&self, ip: Option<IpAddr>, load: f64, now: Instant) -> u32 {
+        // Two more bits, so four times the work, once the providers are fully busy, and more as
+        // requests queue up.
+        let load_bits = ((load - 0.5).max(0.0) * 4.0).round() as u32;
+        let abuse_bits = ip
+            .and_then(|ip| {
+                let abuse = self.abuse.lock().unwrap();
+                abuse.get(&ip).map(|score| score.decayed(now))
+            })
+            .unwrap_or_default() as u32;
+        (self.config.base_difficulty + load_bits + abuse_bits).min(self.config.max_difficulty)
+    }
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+fn random_id() -> String {
+    rand::random::<[u8; 16]>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn proof_of_work() -> ProofOfWork {
+        ProofOfWork::new(PowConfig {
+            origins: HashSet::from(["*".to_string()]),
+            base_difficulty: 4,
+            max_difficulty: 12,
+            allowance_prompts: 2,
+            ..PowConfig::default()
+        })
+    }
+
+    fn solve(challenge: &IssuedChallenge) -> String {
+        (0u64..)
+            .map(|counter| counter.to_string())
+            .find(|solution| {
+                let hash = Sha256::digest(format!("{}:{}", challenge.challenge, solution));
+                leading_zero_bits(&hash) >= challenge.difficulty
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn trades_solutions_for_allowances() {
+        let pow = proof_of_work();
+        assert_eq!(pow.spend(None), Err(PowError::ChallengeRequired));
+
+        let challenge = pow.issue(None, 0.0);
+        assert_eq!(challenge.difficulty, 4);
+        let allowance = pow
+            .solve(&challenge.challenge, &solve(&challenge), None)
+            .unwrap();
+
+        assert_eq!(pow.check(Some(&allowance.allowance)), Ok(()));
+        assert_eq!(pow.spend(Some(&allowance.allowance)), Ok(()));
+        assert_eq!(pow.spend(Some(&allowance.allowance)), Ok(()));
+        assert_eq!(
+            pow.check(Some(&allowance.allowance)),
+            Err(PowError::ChallengeRequired)
+        );
+    }
+
+    #[test]
+    fn challenges_are_solved_once() {
+        let pow = proof_of_work();
+        let challenge = pow.issue(Some(IP), 0.0);
+        let solution = solve(&challenge);
+
+        assert!(pow.solve(&challenge.challenge, &solution, Some(IP)).is_ok());
+        assert_eq!(
+            pow.solve(&challenge.challenge, &solution, Some(IP))
+                .unwrap_err(),
+            PowError::UnknownChallenge
+        );
+    }
+
+    #[test]
+    fn refuses_tampered_and_expired_challenges() {
+        let pow = proof_of_work();
+        let challenge = pow.issue(None, 0.0);
+        let solution = solve(&challenge);
+
+        let easier = challenge.challenge.replacen(".4.", ".0.", 1);
+        assert_eq!(
+            pow.solve(&easier, &solution, None).unwrap_err(),
+            PowError::UnknownChallenge
+        );
+        assert_eq!(
+            pow.solve_at(&challenge.challenge, &solution, None, challenge.expires_at)
+                .unwrap_err(),
+            PowError::UnknownChallenge
+        );
+    }
+
+    #[test]
+    fn forgets_solved_challenges_once_they_expire() {
+        let mut solved = Solved::default();
+
+        assert!(solved.insert("a", 100, 0));
+        assert!(!solved.insert("a", 100, 50));
+        assert!(solved.insert("b", 200, 100));
+        assert_eq!(solved.nonces, HashSet::from(["b".to_string()]));
+    }
+
+    #[test]
+    fn hardens_challenges_with_load_and_abuse() {
+        let pow = proof_of_work();
+        let now = Instant::now();
+        assert_eq!(pow.difficulty(Some(IP), 0.0, now), 4);
+        assert_eq!(pow.difficulty(Some(IP), 1.0, now), 6);
+        assert_eq!(pow.difficulty(Some(IP), 2.0, now), 10);
+
+        pow.report_abuse(IP, 3.0);
+        assert_eq!(pow.difficulty(Some(IP), 0.0, now), 7);
+        assert_eq!(pow.difficulty(Some(IP), 2.0, now), 12);
+        assert_eq!(pow.difficulty(Some(IP), 0.0, now + ABUSE_HALF_LIFE * 2), 4);
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0, 0b0001_0000, 0]), 11);
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0, 0]), 16);
+    }
+}
@@ -0,0 +1,134 @@
+use axum::{
+    Json, Router,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{StatusCode, header, request::Parts},
+    routing::{delete, get, post},
+};
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    AppState,
+    api_keys::{ApiKey, NewApiKey},
+    ledger::{DailyUsage, UsageQuery},
+};
+
+use super::{error::ApplicationError, extract::AppJson};
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/usage", get(usage))
+        .route("/keys", get(list_keys).post(create_key))
+        .route("/keys/{id}", delete(revoke_key))
+        .route("/keys/{id}/rotate", post(rotate_key))
+}
+
+#[derive(Debug, Serialize)]
+struct UsageResponse {
+    usage: Vec<DailyUsage>,
+}
+
+/// Reports the usage of each origin and API key on each day.
+#[axum::debug_handler]
+async fn usage(
+    State(app_state): State<AppState>,
+    _: Admin,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<UsageResponse>, ApplicationError> {
+    info!(query = ?query, "usage request");
+
+    let usage = app_state.ledger.daily_usage(query).await?;
+    Ok(Json(UsageResponse { usage }))
+}
+
+#[derive(Debug, Serialize)]
+struct KeysResponse {
+    keys: Vec<ApiKey>,
+}
+
+// A key with its secret, which is only shown when the key is created or rotated.
+#[derive(Debug, Serialize)]
+struct IssuedKey {
+    #[serde(flatten)]
+    key: ApiKey,
+    secret: String,
+}
+
+#[axum::debug_handler]
+async fn list_keys(State(app_state): State<AppState>, _: Admin) -> Json<KeysResponse> {
+    Json(KeysResponse {
+        keys: app_state.api_keys.list(),
+    })
+}
+
+#[axum::debug_handler]
+async fn create_key(
+    State(app_state): State<AppState>,
+    _: Admin,
+    AppJson(new_key): AppJson<NewApiKey>,
+) -> Result<(StatusCode, Json<IssuedKey>), ApplicationError> {
+    let (key, secret) = app_state.api_keys.create(new_key)?;
+    info!(key = %key.id, name = %key.name, "API key created.");
+    Ok((StatusCode::CREATED, Json(IssuedKey { key, secret })))
+}
+
+#[axum::debug_handler]
+async fn rotate_key(
+    State(app_state): State<AppState>,
+    _: Admin,
+    Path(id): Path<String>,
+) -> Result<Json<IssuedKey>, ApplicationError> {
+    let (key, secret) = app_state
+        .api_keys
+        .rotate(&id)?
+        .ok_or(ApplicationError::ApiKeyNotFound)?;
+    info!(key = %key.id, "API key rotated.");
+    Ok(Json(IssuedKey { key, secret }))
+}
+
+#[axum::debug_handler]
+async fn revoke_key(
+    State(app_state): State<AppState>,
+    _: Admin,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApplicationError> {
+    if !app_state.api_keys.revoke(&id)? {
+        return Err(ApplicationError::ApiKeyNotFound);
+    }
+    info!(key = %id, "API key revoked.");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Proof that the request carries the admin token, as a bearer token. Without an `ADMIN_TOKEN`, the
+// admin endpoints refuse every request.
+struct Admin;
+
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = ApplicationError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match (&state.admin_token, token) {
+            (Some(expected), Some(token))
+                if constant_time_eq(expected.as_bytes(), token.as_bytes()) =>
+            {
+                Ok(Admin)
+            }
+            _ => Err(ApplicationError::Unauthorized(
+                "Missing or invalid admin token.",
+            )),
+        }
+    }
+}
+
+// Compares secrets without leaking how much of them matched through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
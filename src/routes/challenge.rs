@@ -0,0 +1,49 @@
+use axum::{Json, Router, extract::State, routing::post};
+use serde::Deserialize;
+
+use crate::{
+    AppState,
+    limits::Subjects,
+    proof_of_work::{IssuedAllowance, IssuedChallenge},
+};
+
+use super::{error::ApplicationError, extract::AppJson};
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/challenge", post(issue_challenge))
+        .route("/challenge/solve", post(solve_challenge))
+}
+
+#[derive(Debug, Deserialize)]
+struct Solution {
+    challenge: String,
+    solution: String,
+}
+
+/// Issues a proof-of-work challenge, harder when the providers are busy or the client misbehaved.
+#[axum::debug_handler]
+async fn issue_challenge(
+    State(app_state): State<AppState>,
+    subjects: Subjects,
+) -> Json<IssuedChallenge> {
+    Json(
+        app_state
+            .proof_of_work
+            .issue(subjects.ip(), app_state.upstream.load()),
+    )
+}
+
+/// Trades the solution of a challenge for an allowance of prompts.
+#[axum::debug_handler]
+async fn solve_challenge(
+    State(app_state): State<AppState>,
+    subjects: Subjects,
+    AppJson(solution): AppJson<Solution>,
+) -> Result<Json<IssuedAllowance>, ApplicationError> {
+    let allowance =
+        app_state
+            .proof_of_work
+            .solve(&solution.challenge, &solution.solution, subjects.ip())?;
+    Ok(Json(allowance))
+}
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use axum::{Extension, Json, Router, extract::State, routing::post};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    AppState, api_keys::ApiKey, client_tokens::ClientToken, ledger::unix_now, limits::Quota,
+};
+
+use super::{error::ApplicationError, extract::AppJson};
+
+const DEFAULT_TTL_SECS: i64 = 10 * 60;
+const MAX_TTL_SECS: i64 = 60 * 60;
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/tokens", post(issue_token))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenRequest {
+    origin: String,
+    #[serde(default)]
+    endpoints: Vec<String>,
+    #[serde(default)]
+    models: Vec<String>,
+    limits: Option<Quota>,
+    ttl_secs: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenResponse {
+    token: String,
+    expires_at: i64,
+}
+
+/// Issues a client token to the backend of a site, authenticated with an API key. The token can't
+/// allow more than the key does, nor outlive it.
+#[axum::debug_handler]
+async fn issue_token(
+    State(app_state): State<AppState>,
+    key: Option<Extension<Arc<ApiKey>>>,
+    AppJson(request): AppJson<TokenRequest>,
+) -> Result<Json<TokenResponse>, ApplicationError> {
+    let Some(Extension(key)) = key else {
+        return Err(ApplicationError::Unauthorized(
+            "Issuing client tokens takes an API key.",
+        ));
+    };
+    let signer = app_state
+        .token_signer
+        .as_ref()
+        .ok_or(ApplicationError::Forbidden(
+            "Client tokens are not enabled.",
+        ))?;
+
+    let endpoints = narrow(&key.endpoints, request.endpoints, |endpoint| {
+        key.allows_endpoint(endpoint)
+    })
+    .ok_or(ApplicationError::Forbidden(
+        "The API key may not grant these endpoints.",
+    ))?;
+    let models = narrow(&key.models, request.models, |model| key.allows_model(model)).ok_or(
+        ApplicationError::Forbidden("The API key may not grant these models."),
+    )?;
+
+    let now = unix_now();
+    let ttl = request
+        .ttl_secs
+        .unwrap_or(DEFAULT_TTL_SECS)
+        .clamp(1, MAX_TTL_SECS);
+    let token = ClientToken {
+        id: Uuid::new_v4().simple().to_string(),
+        key: key.id.clone(),
+        origin: request.origin,
+        endpoints,
+        models,
+        limits: request.limits,
+        issued_at: now,
+        expires_at: (now + ttl).min(key.expires_at.unwrap_or(i64::MAX)),
+    };
+    info!(key = %key.id, token = %token.id, origin = %token.origin, "Client token issued.");
+
+    Ok(Json(TokenResponse {
+        token: signer.sign(&token),
+        expires_at: token.expires_at,
+    }))
+}
+
+// The scopes of a token: the requested ones if the key allows them all, or the key's own when none
+// are requested.
+fn narrow(
+    allowed: &[String],
+    requested: Vec<String>,
+    allows: impl Fn(&str) -> bool,
+) -> Option<Vec<String>> {
+    if requested.is_empty() {
+        return Some(allowed.to_vec());
+    }
+    requested
+        .iter()
+        .all(|scope| allows(scope))
+        .then_some(requested)
+}
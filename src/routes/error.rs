@@ -1,16 +1,265 @@
 use gemini_rs::error::Error as GeminiError;
+use serde::Serialize;
 use std::error::Error;
 use std::fmt::Display;
+use std::time::Duration;
 use tracing::error;
 
-use axum::response::IntoResponse;
+use axum::{
+    Json,
+    extract::rejection::BytesRejection,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
 
+use crate::{
+    client_tokens::TokenError, limits::LimitExceeded, middleware::request_id::RequestId,
+    proof_of_work::PowError,
+};
+
+use super::schema::InvalidBody;
 use built_in_hybrid_server::ai::language_model::AILanguageModelError;
 
 #[derive(Debug)]
 pub enum ApplicationError {
     LanguageModelError(AILanguageModelError),
     GeminiError(GeminiError),
+    BodyRejection(BytesRejection),
+    UnsupportedMediaType,
+    InvalidBody(InvalidBody),
+    Multipart(multer::Error),
+    TemporaryStorage(std::io::Error),
+    InvalidResumeToken(&'static str),
+    InvalidRequestTimeout,
+    GenerationNotFound,
+    ForbiddenOrigin,
+    LimitExceeded(LimitExceeded),
+    Unauthorized(&'static str),
+    InvalidApiKey,
+    InvalidClientToken(TokenError),
+    Forbidden(&'static str),
+    ApiKeyNotFound,
+    LedgerError(rusqlite::Error),
+    ProofOfWork(PowError),
+}
+
+/// A stable, machine readable error code. Each code maps to the `DOMException` the built-in
+/// Prompt API throws in the same situation, so the fallback can throw the same exception.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    UnsupportedInput,
+    UnsupportedOptions,
+    PayloadTooLarge,
+    NotAllowed,
+    ChallengeRequired,
+    InvalidState,
+    Aborted,
+    SafetyBlocked,
+    RateLimited,
+    QuotaExceeded,
+    Timeout,
+    UpstreamUnavailable,
+    Overloaded,
+    TooManyRequests,
+    RateLimitExceeded,
+    BudgetExceeded,
+    UpstreamError,
+    InternalError,
+}
+
+impl ErrorCode {
+    /// The name of the `DOMException` the built-in Prompt API throws for this error.
+    pub fn exception_name(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidRequest => "SyntaxError",
+            ErrorCode::UnsupportedInput | ErrorCode::UnsupportedOptions => "NotSupportedError",
+            ErrorCode::PayloadTooLarge => "QuotaExceededError",
+            ErrorCode::NotAllowed | ErrorCode::ChallengeRequired => "NotAllowedError",
+            ErrorCode::InvalidState => "InvalidStateError",
+            ErrorCode::Aborted => "AbortError",
+            ErrorCode::SafetyBlocked => "NotAllowedError",
+            ErrorCode::RateLimited
+            | ErrorCode::QuotaExceeded
+            | ErrorCode::TooManyRequests
+            | ErrorCode::RateLimitExceeded
+            | ErrorCode::BudgetExceeded => "QuotaExceededError",
+            ErrorCode::Timeout => "TimeoutError",
+            ErrorCode::UpstreamUnavailable
+            | ErrorCode::Overloaded
+            | ErrorCode::UpstreamError
+            | ErrorCode::InternalError => "UnknownError",
+        }
+    }
+}
+
+impl From<&AILanguageModelError> for ErrorCode {
+    fn from(err: &AILanguageModelError) -> Self {
+        match err {
+            AILanguageModelError::SystemPromptError(_)
+            | AILanguageModelError::PromptInputError(_)
+            | AILanguageModelError::InvalidArgumentError(_) => ErrorCode::InvalidRequest,
+            AILanguageModelError::UnsupportedInputError(_) => ErrorCode::UnsupportedInput,
+            AILanguageModelError::CreateOptionsError(_) => ErrorCode::UnsupportedOptions,
+            AILanguageModelError::SafetyBlockedError(_) => ErrorCode::SafetyBlocked,
+            AILanguageModelError::RateLimitedError(_) => ErrorCode::RateLimited,
+            AILanguageModelError::QuotaExceededError(_) => ErrorCode::QuotaExceeded,
+            AILanguageModelError::TimeoutError(_) => ErrorCode::Timeout,
+            AILanguageModelError::UnavailableError(_) => ErrorCode::UpstreamUnavailable,
+            AILanguageModelError::OverloadedError(_) => ErrorCode::Overloaded,
+            AILanguageModelError::TooManyRequestsError(_) => ErrorCode::TooManyRequests,
+            AILanguageModelError::AuthenticationError(_)
+            | AILanguageModelError::ProviderError(_) => ErrorCode::UpstreamError,
+        }
+    }
+}
+
+// The body of an error response.
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: ErrorDetails,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorDetails {
+    code: ErrorCode,
+    name: &'static str,
+    message: String,
+    retryable: bool,
+    request_id: Option<String>,
+    /// A JSON pointer to the value of the request body that is invalid.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pointer: Option<String>,
+}
+
+/// The message clients get in place of a language model error's own, when the error comes from
+/// the provider: its messages may describe our setup, such as the project and location in a
+/// request URL, so they only go to the logs.
+pub fn masked_message(err: &AILanguageModelError) -> Option<&'static str> {
+    Some(match err {
+        AILanguageModelError::RateLimitedError(_) => {
+            "The model provider is receiving too many requests."
+        }
+        AILanguageModelError::QuotaExceededError(_)
+        | AILanguageModelError::AuthenticationError(_) => "The model provider is not available.",
+        AILanguageModelError::InvalidArgumentError(_) => {
+            "The model provider rejected the request as invalid."
+        }
+        AILanguageModelError::TimeoutError(_) => "The model provider did not answer in time.",
+        AILanguageModelError::UnavailableError(_) => {
+            "The model provider is temporarily unavailable."
+        }
+        AILanguageModelError::ProviderError(_) => "The model provider failed.",
+        _ => return None,
+    })
+}
+
+impl ApplicationError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ApplicationError::LanguageModelError(err) => err.into(),
+            ApplicationError::GeminiError(_) => ErrorCode::UpstreamError,
+            ApplicationError::BodyRejection(rejection)
+                if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE =>
+            {
+                ErrorCode::PayloadTooLarge
+            }
+            ApplicationError::Multipart(
+                multer::Error::StreamSizeExceeded { .. } | multer::Error::FieldSizeExceeded { .. },
+            ) => ErrorCode::PayloadTooLarge,
+            ApplicationError::BodyRejection(_)
+            | ApplicationError::Multipart(_)
+            | ApplicationError::UnsupportedMediaType
+            | ApplicationError::InvalidBody(_)
+            | ApplicationError::InvalidResumeToken(_)
+            | ApplicationError::InvalidRequestTimeout => ErrorCode::InvalidRequest,
+            ApplicationError::GenerationNotFound | ApplicationError::ApiKeyNotFound => {
+                ErrorCode::InvalidState
+            }
+            ApplicationError::ForbiddenOrigin
+            | ApplicationError::Unauthorized(_)
+            | ApplicationError::InvalidApiKey
+            | ApplicationError::InvalidClientToken(_)
+            | ApplicationError::Forbidden(_) => ErrorCode::NotAllowed,
+            ApplicationError::LedgerError(_) | ApplicationError::TemporaryStorage(_) => {
+                ErrorCode::InternalError
+            }
+            ApplicationError::ProofOfWork(PowError::ChallengeRequired) => {
+                ErrorCode::ChallengeRequired
+            }
+            ApplicationError::ProofOfWork(PowError::InvalidSolution) => ErrorCode::InvalidRequest,
+            ApplicationError::ProofOfWork(PowError::UnknownChallenge) => ErrorCode::InvalidState,
+            ApplicationError::LimitExceeded(exceeded) if exceeded.kind.is_budget() => {
+                ErrorCode::BudgetExceeded
+            }
+            ApplicationError::LimitExceeded(_) => ErrorCode::RateLimitExceeded,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ApplicationError::LanguageModelError(err) => match ErrorCode::from(err) {
+                ErrorCode::RateLimited | ErrorCode::QuotaExceeded | ErrorCode::TooManyRequests => {
+                    StatusCode::TOO_MANY_REQUESTS
+                }
+                ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                ErrorCode::UpstreamUnavailable
+                | ErrorCode::Overloaded
+                | ErrorCode::UpstreamError => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::UNPROCESSABLE_ENTITY,
+            },
+            ApplicationError::GeminiError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApplicationError::BodyRejection(rejection) => rejection.status(),
+            ApplicationError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApplicationError::InvalidBody(invalid) if invalid.malformed => StatusCode::BAD_REQUEST,
+            ApplicationError::InvalidBody(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApplicationError::Multipart(_) if self.code() == ErrorCode::PayloadTooLarge => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            ApplicationError::Multipart(_) => StatusCode::BAD_REQUEST,
+            ApplicationError::InvalidResumeToken(_) | ApplicationError::InvalidRequestTimeout => {
+                StatusCode::BAD_REQUEST
+            }
+            ApplicationError::GenerationNotFound | ApplicationError::ApiKeyNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            ApplicationError::ForbiddenOrigin | ApplicationError::Forbidden(_) => {
+                StatusCode::FORBIDDEN
+            }
+            ApplicationError::LimitExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApplicationError::Unauthorized(_)
+            | ApplicationError::InvalidApiKey
+            | ApplicationError::InvalidClientToken(_) => StatusCode::UNAUTHORIZED,
+            ApplicationError::LedgerError(_) | ApplicationError::TemporaryStorage(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            ApplicationError::ProofOfWork(PowError::ChallengeRequired) => StatusCode::UNAUTHORIZED,
+            ApplicationError::ProofOfWork(PowError::InvalidSolution) => StatusCode::BAD_REQUEST,
+            ApplicationError::ProofOfWork(PowError::UnknownChallenge) => StatusCode::NOT_FOUND,
+        }
+    }
+
+    /// Whether repeating the same request may succeed, possibly after [`Self::retry_after`].
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ApplicationError::LanguageModelError(err) => {
+                err.is_retryable() || self.retry_after().is_some()
+            }
+            ApplicationError::GeminiError(_) | ApplicationError::LimitExceeded(_) => true,
+            _ => false,
+        }
+    }
+
+    /// How long the client should wait before repeating the request, if known.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ApplicationError::LanguageModelError(err) => err.upstream()?.retry_after,
+            ApplicationError::LimitExceeded(exceeded) => Some(exceeded.status.reset),
+            _ => None,
+        }
+    }
 }
 
 impl Error for ApplicationError {}
@@ -20,6 +269,32 @@ impl Display for ApplicationError {
         match self {
             ApplicationError::LanguageModelError(err) => write!(f, "{}", err),
             ApplicationError::GeminiError(err) => write!(f, "{}", err),
+            ApplicationError::BodyRejection(rejection) => write!(f, "{}", rejection.body_text()),
+            ApplicationError::UnsupportedMediaType => {
+                write!(f, "Expected request with `Content-Type: application/json`.")
+            }
+            ApplicationError::InvalidBody(invalid) => write!(f, "{}", invalid.message),
+            ApplicationError::Multipart(err) => write!(f, "{}", err),
+            ApplicationError::TemporaryStorage(err) => write!(f, "{}", err),
+            ApplicationError::InvalidResumeToken(msg) => write!(f, "{}", msg),
+            ApplicationError::InvalidRequestTimeout => write!(
+                f,
+                "X-Request-Timeout must be a positive number of milliseconds."
+            ),
+            ApplicationError::GenerationNotFound => {
+                write!(f, "The generation is unknown or no longer resumable.")
+            }
+            ApplicationError::ForbiddenOrigin => write!(f, "Forbidden"),
+            ApplicationError::LimitExceeded(exceeded) => write!(f, "{}", exceeded),
+            ApplicationError::Unauthorized(msg) => write!(f, "{}", msg),
+            ApplicationError::InvalidClientToken(err) => write!(f, "{}", err),
+            ApplicationError::InvalidApiKey => {
+                write!(f, "The API key is unknown, revoked or expired.")
+            }
+            ApplicationError::Forbidden(msg) => write!(f, "{}", msg),
+            ApplicationError::ApiKeyNotFound => write!(f, "The API key is unknown or revoked."),
+            ApplicationError::LedgerError(err) => write!(f, "{}", err),
+            ApplicationError::ProofOfWork(err) => write!(f, "{}", err),
         }
     }
 }
@@ -36,28 +311,175 @@ impl From<GeminiError> for ApplicationError {
     }
 }
 
+impl From<LimitExceeded> for ApplicationError {
+    fn from(exceeded: LimitExceeded) -> Self {
+        ApplicationError::LimitExceeded(exceeded)
+    }
+}
+
+impl From<rusqlite::Error> for ApplicationError {
+    fn from(err: rusqlite::Error) -> Self {
+        ApplicationError::LedgerError(err)
+    }
+}
+
+impl From<PowError> for ApplicationError {
+    fn from(err: PowError) -> Self {
+        ApplicationError::ProofOfWork(err)
+    }
+}
+
+impl From<BytesRejection> for ApplicationError {
+    fn from(rejection: BytesRejection) -> Self {
+        ApplicationError::BodyRejection(rejection)
+    }
+}
+
+impl From<multer::Error> for ApplicationError {
+    fn from(err: multer::Error) -> Self {
+        ApplicationError::Multipart(err)
+    }
+}
+
+impl From<std::io::Error> for ApplicationError {
+    fn from(err: std::io::Error) -> Self {
+        ApplicationError::TemporaryStorage(err)
+    }
+}
+
+impl From<InvalidBody> for ApplicationError {
+    fn from(invalid: InvalidBody) -> Self {
+        ApplicationError::InvalidBody(invalid)
+    }
+}
+
 impl IntoResponse for ApplicationError {
-    fn into_response(self) -> axum::response::Response {
-        match self {
-            ApplicationError::LanguageModelError(err) => {
-                let status_code = match err {
-                    AILanguageModelError::SystemPromptError(_) => {
-                        axum::http::StatusCode::BAD_REQUEST
-                    }
-                    AILanguageModelError::PromptInputError(_) => {
-                        axum::http::StatusCode::BAD_REQUEST
-                    }
-                    AILanguageModelError::ProviderError(_) => {
-                        axum::http::StatusCode::INTERNAL_SERVER_ERROR
-                    }
-                };
-                (status_code, err.to_string()).into_response()
-            }
-            ApplicationError::GeminiError(err) => {
+    fn into_response(self) -> Response {
+        let code = self.code();
+        let status_code = self.status_code();
+
+        // Upstream failures may describe our setup, so their details only go to the logs.
+        let message = match self {
+            ApplicationError::LanguageModelError(ref err) => match masked_message(err) {
+                Some(message) => {
+                    error!(source = ?err.source(), "Gemini error: {}", err);
+                    message.to_string()
+                }
+                None => err.to_string(),
+            },
+            ApplicationError::GeminiError(ref err) => {
                 error!("Gemini error: {}", err);
-                let status_code = axum::http::StatusCode::INTERNAL_SERVER_ERROR;
-                (status_code, "Internal Server Error").into_response()
+                "Internal Server Error".to_string()
             }
+            ApplicationError::LedgerError(ref err) => {
+                error!("Ledger error: {}", err);
+                "Internal Server Error".to_string()
+            }
+            ApplicationError::TemporaryStorage(ref err) => {
+                error!("Temporary storage error: {}", err);
+                "Internal Server Error".to_string()
+            }
+            _ => self.to_string(),
+        };
+
+        let body = ErrorBody {
+            error: ErrorDetails {
+                code,
+                name: code.exception_name(),
+                message,
+                retryable: self.is_retryable(),
+                request_id: RequestId::current(),
+                pointer: match &self {
+                    ApplicationError::InvalidBody(invalid) if !invalid.pointer.is_empty() => {
+                        Some(invalid.pointer.clone())
+                    }
+                    _ => None,
+                },
+            },
+        };
+        let mut response = (status_code, Json(body)).into_response();
+        if let Some(retry_after) = self.retry_after() {
+            // Retry-After takes whole seconds, rounded up so the client doesn't retry too early.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, seconds.into());
+        }
+        if let ApplicationError::LimitExceeded(exceeded) = &self {
+            exceeded.status.add_headers(response.headers_mut());
         }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use built_in_hybrid_server::ai::language_model::UpstreamError;
+
+    #[test]
+    fn maps_language_model_errors_to_exceptions() {
+        let err = ApplicationError::from(AILanguageModelError::UnsupportedInputError(
+            "Unsupported input type",
+        ));
+
+        assert_eq!(err.code(), ErrorCode::UnsupportedInput);
+        assert_eq!(err.code().exception_name(), "NotSupportedError");
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn maps_upstream_errors_to_statuses() {
+        let rate_limited = AILanguageModelError::RateLimitedError(
+            UpstreamError::new("Slow down").with_status(429),
+        );
+        let err = ApplicationError::from(rate_limited);
+
+        assert_eq!(err.code(), ErrorCode::RateLimited);
+        assert_eq!(err.status_code(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(err.is_retryable());
+
+        let unavailable = AILanguageModelError::UnavailableError(UpstreamError::new("Down"));
+        assert_eq!(
+            ApplicationError::from(unavailable).status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let failed = AILanguageModelError::ProviderError(UpstreamError::new("Broken"));
+        assert_eq!(
+            ApplicationError::from(failed).status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[test]
+    fn masks_provider_messages() {
+        let failed = AILanguageModelError::ProviderError(UpstreamError::new(
+            "error sending request for url (https://example.com/projects/p/locations/l)",
+        ));
+        assert_eq!(masked_message(&failed), Some("The model provider failed."));
+
+        let invalid = AILanguageModelError::CreateOptionsError("A topK is required.");
+        assert_eq!(masked_message(&invalid), None);
+    }
+
+    #[test]
+    fn serializes_error_body() {
+        let body = ErrorBody {
+            error: ErrorDetails {
+                code: ErrorCode::InvalidState,
+                name: ErrorCode::InvalidState.exception_name(),
+                message: "Gone".to_string(),
+                retryable: false,
+                request_id: Some("abc".to_string()),
+                pointer: None,
+            },
+        };
+
+        assert_eq!(
+            serde_json::to_string(&body).unwrap(),
+            r#"{"error":{"code":"invalid_state","name":"InvalidStateError","message":"Gone","retryable":false,"requestId":"abc"}}"#
+        );
     }
 }
@@ -0,0 +1,246 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, FromRequest, FromRequestParts, Request},
+    http::{HeaderMap, HeaderName, header, request::Parts},
+};
+use serde::de::DeserializeOwned;
+use tokio::time::Instant;
+
+use super::{error::ApplicationError, schema::from_json};
+use crate::{
+    AppState,
+    api_keys::ApiKey,
+    client_tokens::ClientToken,
+    limits::{Subject, Subjects},
+    origins::OriginPolicy,
+    upstream::ClientId,
+};
+use built_in_hybrid_server::ai::language_model::AILanguageModelGuardrails;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// The request header clients send their proof-of-work allowance in.
+pub const ALLOWANCE_HEADER: HeaderName = HeaderName::from_static("x-pow-allowance");
+
+/// The request header clients use to shorten the request timeout, in milliseconds.
+pub const REQUEST_TIMEOUT_HEADER: HeaderName = HeaderName::from_static("x-request-timeout");
+
+/// Like [`axum::Json`], but rejects malformed bodies with a structured [`ApplicationError`]
+/// pointing at the value that didn't fit, instead of a plain text response.
+#[derive(Debug)]
+pub struct AppJson<T>(pub T);
+
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for AppJson<T> {
+    type Rejection = ApplicationError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_json = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|essence| essence.trim().to_ascii_lowercase())
+            .is_some_and(|essence| {
+                essence == "application/json"
+                    || (essence.starts_with("application/") && essence.ends_with("+json"))
+            });
+        if !is_json {
+            return Err(ApplicationError::UnsupportedMediaType);
+        }
+
+        let body = Bytes::from_request(req, state).await?;
+        Ok(AppJson(from_json(&body)?))
+    }
+}
+
+/// The time by which the request must complete, including retries. Clients can bring it forward
+/// with the `X-Request-Timeout` header, but not past the server's request timeout.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(pub Instant);
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Deadline(Instant::now() + timeout)
+    }
+}
+
+impl FromRequestParts<AppState> for Deadline {
+    type Rejection = ApplicationError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(REQUEST_TIMEOUT_HEADER) else {
+            return Ok(Deadline::after(state.request_timeout));
+        };
+
+        let millis = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .filter(|millis| *millis > 0)
+            .ok_or(ApplicationError::InvalidRequestTimeout)?;
+        Ok(Deadline::after(
+            Duration::from_millis(millis).min(state.request_timeout),
+        ))
+    }
+}
+
+// Requests are queued per API key, or per origin, as every other request to the language model
+// routes comes from an allowed origin.
+impl<S: Send + Sync> FromRequestParts<S> for ClientId {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(key) = parts.extensions.get::<Arc<ApiKey>>() {
+            return Ok(ClientId(format!("key:{}", key.id)));
+        }
+
+        let origin = parts
+            .headers
+            .get(header::ORIGIN)
+            .and_then(|origin| origin.to_str().ok())
+            .unwrap_or_default();
+        Ok(ClientId(origin.to_string()))
+    }
+}
+
+// Requests made with an API key are charged to the key alone, as the servers using keys may
+// share addresses. Other requests are charged to their origin and to the client's IP address, and
+// to their client token if it was issued with limits. Behind trusted proxies, the address is the
+// one the outermost proxy saw, from `X-Forwarded-For`. Anonymous requests may also carry
+// a proof-of-work allowance, in the `X-Pow-Allowance` header or the `allowance` query parameter.
+impl FromRequestParts<AppState> for Subjects {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(key) = parts.extensions.get::<Arc<ApiKey>>() {
+            return Ok(Subjects {
+                subjects: vec![Subject::ApiKey(key.id.clone())],
+                api_key: Some(key.clone()),
+                client_token: None,
+                origin_policy: None,
+                guardrails: AILanguageModelGuardrails {
+                    safety_settings: key.guardrails.safety_settings.or(&state.safety_settings),
+                    ..key.guardrails.clone()
+                },
+                allowance: None,
+            });
+        }
+
+        let mut subjects = Vec::new();
+
+        if let Some(origin) = parts
+            .headers
+            .get(header::ORIGIN)
+            .and_then(|origin| origin.to_str().ok())
+        {
+            subjects.push(Subject::Origin(origin.to_string()));
+        }
+
+        let forwarded_for = forwarded_for(&parts.headers, state.trusted_proxies);
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip());
+        if let Some(ip) = forwarded_for.or(peer) {
+            subjects.push(Subject::Ip(ip));
+        }
+
+        let client_token = parts.extensions.get::<Arc<ClientToken>>().cloned();
+        if let Some(token) = &client_token
+            && token.limits.is_some()
+        {
+            subjects.push(Subject::ClientToken(token.id.clone()));
+        }
+
+        let allowance = parts
+            .headers
+            .get(ALLOWANCE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .or_else(|| {
+                parts
+                    .uri
+                    .query()?
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("allowance="))
+            })
+            .map(str::to_string);
+
+        // Pages follow the guardrails of their origin, and of the key that issued their token.
+        let origin_policy = parts.extensions.get::<Arc<OriginPolicy>>().cloned();
+        let mut guardrails = origin_policy
+            .as_ref()
+            .map(|policy| policy.guardrails.clone())
+            .unwrap_or_default();
+        if let Some(key) = client_token
+            .as_ref()
+            .and_then(|token| state.api_keys.get(&token.key))
+        {
+            guardrails = guardrails.and(&key.guardrails);
+        }
+        guardrails.safety_settings = guardrails.safety_settings.or(&state.safety_settings);
+
+        Ok(Subjects {
+            subjects,
+            api_key: None,
+            client_token,
+            origin_policy,
+            guardrails,
+            allowance,
+        })
+    }
+}
+
+// The client's address as the outermost of `trusted_proxies` proxies saw it. Each proxy appends
+// the address it got the request from to `X-Forwarded-For`, so the entries left of the one the
+// outermost proxy appended come from the client, and may be forged.
+fn forwarded_for(headers: &HeaderMap, trusted_proxies: usize) -> Option<IpAddr> {
+    let entries: Vec<&str> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    let index = entries.len().checked_sub(trusted_proxies)?;
+    // Without trusted proxies, the index is past the last entry.
+    entries.get(index).and_then(|ip| ip.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_the_address_the_outermost_trusted_proxy_saw() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            "203.0.113.9, 198.51.100.7".parse().unwrap(),
+        );
+        headers.append(X_FORWARDED_FOR, "192.0.2.1".parse().unwrap());
+
+        // The client made up the leading entries.
+        assert_eq!(
+            forwarded_for(&headers, 1),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(
+            forwarded_for(&headers, 2),
+            Some("198.51.100.7".parse().unwrap())
+        );
+        assert_eq!(forwarded_for(&headers, 0), None);
+        assert_eq!(forwarded_for(&headers, 4), None);
+    }
+}
@@ -0,0 +1,126 @@
+use built_in_hybrid_server::ai::{
+    image,
+    language_model::{AILanguageModel, AILanguageModelPrompt, providers::GeminiProvider},
+};
+use tokio::sync::Semaphore;
+
+use super::schema::InvalidBody;
+
+/// The most images a request may hold, across its lists of prompts.
+pub const MAX_IMAGES: usize = 16;
+/// The most bytes of images a request may send to the model, once they are normalized.
+pub const MAX_IMAGES_BYTES: usize = 16 * 1024 * 1024;
+// How many requests may decode their images at once. Decoding takes a blocking thread and up to a
+// few hundred MiB of memory, so the others wait their turn.
+const MAX_CONCURRENT_DECODES: usize = 4;
+
+static DECODES: Semaphore = Semaphore::const_new(MAX_CONCURRENT_DECODES);
+
+/// Normalizes the images of a request's lists of prompts, each list given with a JSON pointer to
+/// it, so that they go to the model upright, no larger than it takes, and without metadata.
+/// Decoding runs on a blocking thread, as large images take a while, and only for a few requests
+/// at once.
+pub async fn normalize_images(
+    lists: Vec<(&str, &mut [AILanguageModelPrompt])>,
+) -> Result<(), InvalidBody> {
+    let mut images: Vec<(String, &mut Vec<u8>)> = lists
+        .into_iter()
+        .flat_map(|(list, prompts)| {
+            prompts
+                .iter_mut()
+                .enumerate()
+                .filter_map(move |(index, prompt)| match prompt {
+                    AILanguageModelPrompt::Image { content, .. } => {
+                        Some((format!("{}/{}/content", list, index), content))
+                    }
+                    _ => None,
+                })
+        })
+        .collect();
+    if images.is_empty() {
+        return Ok(());
+    }
+    if let Some((pointer, _)) = images.get(MAX_IMAGES) {
+        return Err(InvalidBody::new(
+            pointer.clone(),
+            format!("At most {} images are allowed in a request.", MAX_IMAGES),
+        ));
+    }
+
+    let originals: Vec<Vec<u8>> = images
+        .iter_mut()
+        .map(|(_, content)| std::mem::take(*content))
+        .collect();
+    let max_dimension = GeminiProvider::capabilities().max_image_dimension;
+    let permit = DECODES
+        .acquire()
+        .await
+        .expect("The decoding semaphore is never closed");
+    // The permit moves to the blocking thread, which keeps decoding when the request is dropped.
+    let normalized = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        originals
+            .iter()
+            .map(|original| image::normalize(original, max_dimension))
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(|_| InvalidBody::malformed("The images could not be processed."))?;
+
+    let mut total_bytes = 0;
+    for ((pointer, content), normalized) in images.into_iter().zip(normalized) {
+        let normalized =
+            normalized.map_err(|e| InvalidBody::new(pointer.clone(), e.to_string()))?;
+        total_bytes += normalized.len();
+        if total_bytes > MAX_IMAGES_BYTES {
+            return Err(InvalidBody::new(
+                pointer,
+                format!(
+                    "The images of a request may take at most {} bytes once scaled down.",
+                    MAX_IMAGES_BYTES
+                ),
+            ));
+        }
+        *content = normalized;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use ::image::{DynamicImage, ImageFormat, RgbImage};
+
+    use super::*;
+
+    fn image_prompt(content: Vec<u8>) -> AILanguageModelPrompt {
+        AILanguageModelPrompt::Image {
+            role: Default::default(),
+            content,
+        }
+    }
+
+    #[tokio::test]
+    async fn normalizes_images_and_points_at_invalid_ones() {
+        let mut bmp = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(8, 8))
+            .write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)
+            .unwrap();
+
+        let mut inputs = vec![image_prompt(bmp.clone())];
+        normalize_images(vec![("/inputs", &mut inputs)])
+            .await
+            .unwrap();
+        let AILanguageModelPrompt::Image { content, .. } = &inputs[0] else {
+            unreachable!()
+        };
+        assert_eq!(image::mime_type(content), Some("image/jpeg"));
+
+        let mut inputs = vec![image_prompt(bmp), image_prompt(b"not an image".to_vec())];
+        let err = normalize_images(vec![("/inputs", &mut inputs)])
+            .await
+            .unwrap_err();
+        assert_eq!(err.pointer, "/inputs/1/content");
+    }
+}
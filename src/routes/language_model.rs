@@ -1,106 +1,503 @@
-use std::convert::Infallible;
-
 use axum::{
     Json, Router,
-    body::Body,
-    extract::State,
-    http::header,
-    response::{AppendHeaders, IntoResponse, Result},
-    routing::post,
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response, Result},
+    routing::{get, post},
 };
 
-use serde::Deserialize;
-use tokio::sync::mpsc::{self, Sender};
-use tokio_stream::{StreamExt, wrappers::ReceiverStream};
-use tracing::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tokio_stream::StreamExt;
+use tracing::{error, info, warn};
+use uuid::Uuid;
 
-use crate::AppState;
-use built_in_hybrid_server::ai::language_model::{
-    AILanguageModel, AILanguageModelCreateOptions, AILanguageModelPrompt, CountTokens, Prompt,
-    PromptTreaming, providers::GeminiProvider,
+use crate::{
+    AppState,
+    generations::{EventId, Generation, Owner},
+    limits::{Reservation, Subjects},
+    upstream::ClientId,
+};
+use built_in_hybrid_server::ai::{
+    language_model::{
+        AILanguageModel, AILanguageModelCreateOptions, AILanguageModelError, AILanguageModelPrompt,
+        AILanguageModelUsage, CountTokens, Prompt, PromptTreaming, UpstreamError,
+        deadline_exceeded, providers::GeminiProvider, with_deadline,
+    },
+    tokenizer,
 };
 
-use super::error::ApplicationError;
+use super::{
+    error::{ApplicationError, ErrorCode},
+    extract::Deadline,
+    images::normalize_images,
+    multipart::{Parts, PromptBody},
+    negotiate::ResponseFormat,
+    schema::{InvalidBody, check_prompts},
+    stream::{Delivered, DoneEvent, ErrorEvent, StreamEvent, StreamFormat},
+};
 
 pub fn routes() -> Router<AppState> {
     Router::new()
         .route("/prompt", post(prompt))
         .route("/prompt-streaming", post(prompt_streaming))
+        .route("/prompt-streaming/resume", get(resume_streaming))
         .route("/count-tokens", post(count_tokens))
         .route("/capabilities", post(capabilities))
+        .route("/generations/{id}/cancel", post(cancel_generation))
 }
 
+/// The response header carrying the id of a streaming generation.
+pub const GENERATION_ID_HEADER: HeaderName = HeaderName::from_static("x-generation-id");
+
+const LAST_EVENT_ID: HeaderName = HeaderName::from_static("last-event-id");
+
 #[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct LanguageModelPromptRequest {
+    #[serde(default)]
     pub create_options: AILanguageModelCreateOptions,
     pub inputs: Vec<AILanguageModelPrompt>,
 }
 
+impl LanguageModelPromptRequest {
+    /// Checks the limits on the number and size of prompts, which deserializing doesn't.
+    pub fn check_limits(&self) -> Result<(), InvalidBody> {
+        check_prompts(
+            "/createOptions/initialPrompts",
+            &self.create_options.initial_prompts,
+        )?;
+        check_prompts("/inputs", &self.inputs)
+    }
+
+    /// Reads the request's binary parts into its prompts, and normalizes its images before they go
+    /// to the model. Both are costly, so this waits until the request is charged, recording it in
+    /// the ledger if a part can't be read or an image is refused.
+    pub async fn load_media(
+        &mut self,
+        parts: Parts,
+        app_state: &AppState,
+        subjects: &Subjects,
+    ) -> Result<(), ApplicationError> {
+        let loaded = async {
+            parts.fill(self).await?;
+            normalize_images(vec![
+                (
+                    "/createOptions/initialPrompts",
+                    &mut self.create_options.initial_prompts,
+                ),
+                ("/inputs", &mut self.inputs),
+            ])
+            .await?;
+            Ok(())
+        };
+        loaded.await.map_err(|e| refused(app_state, subjects, e))
+    }
+}
+
 #[axum::debug_handler]
 pub async fn prompt(
     State(app_state): State<AppState>,
-    Json(request): Json<LanguageModelPromptRequest>,
-) -> Result<impl IntoResponse, ApplicationError> {
+    client: ClientId,
+    subjects: Subjects,
+    Deadline(deadline): Deadline,
+    format: ResponseFormat,
+    PromptBody(mut request, parts): PromptBody,
+) -> Result<Response, ApplicationError> {
     info!(request = ?request, "prompt request");
+    request.check_limits()?;
+    prepare_create_options(&subjects, &mut request.create_options)?;
+    let reservation = reserve(&app_state, &subjects, &request)?;
+    let status = reservation.status();
+    request.load_media(parts, &app_state, &subjects).await?;
 
     let provider = GeminiProvider::new(
         app_state.gemini_client.clone(),
         request.create_options.clone(),
     );
+    let mut entry = app_state.ledger.start(
+        &subjects,
+        GeminiProvider::provider_id(),
+        GeminiProvider::model_id(),
+    );
+    let response = async {
+        let _admission = app_state.upstream.admit(&client, deadline).await?;
+        let breaker = app_state
+            .upstream
+            .breaker(GeminiProvider::provider_id(), GeminiProvider::model_id());
+        with_deadline(
+            deadline,
+            app_state
+                .retry_policy
+                .retry(|| breaker.call(provider.prompt(&request.inputs))),
+        )
+        .await
+    }
+    .await
+    .inspect_err(|e| entry.failed(e.into()))?;
+    if let Some(usage) = response.usage {
+        reservation.settle(&usage);
+        entry.set_usage(usage);
+    }
+    entry.succeeded();
 
-    let text = provider.prompt(&request.inputs).await?;
+    let mut http_response = match format {
+        ResponseFormat::Json => Json(response).into_response(),
+        // Plain text has no room for the block reason, and a cut short answer would pass for a
+        // complete one.
+        ResponseFormat::Text => {
+            if let Some(block_reason) = response.block_reason {
+                return Err(
+                    AILanguageModelError::SafetyBlockedError(UpstreamError::new(format!(
+                        "The response was blocked by the safety filters ({}).",
+                        block_reason
+                    )))
+                    .into(),
+                );
+            }
+            response.text.into_response()
+        }
+    };
+    if let Some(status) = status {
+        status.add_headers(http_response.headers_mut());
+    }
+    Ok(http_response)
+}
 
-    Ok(text)
+/// Validates the create options against the model's capabilities, and puts the guardrails of the
+/// request's origin and API key on them.
+pub fn prepare_create_options(
+    subjects: &Subjects,
+    create_options: &mut AILanguageModelCreateOptions,
+) -> Result<(), ApplicationError> {
+    create_options.validate(GeminiProvider::capabilities())?;
+    create_options.guardrails = subjects.guardrails.clone();
+    Ok(())
+}
+
+/// Charges a prompt request to its subjects' limits, estimating its input tokens with the
+/// tokenizer, and to its proof-of-work allowance when it needs one. Refused requests are recorded
+/// in the ledger right away, and those over their limits make the client's next challenges harder.
+pub fn reserve(
+    app_state: &AppState,
+    subjects: &Subjects,
+    request: &LanguageModelPromptRequest,
+) -> Result<Reservation, ApplicationError> {
+    charge(app_state, subjects, request).map_err(|e| {
+        if let (ApplicationError::LimitExceeded(_), Some(ip)) = (&e, subjects.ip()) {
+            app_state.proof_of_work.report_abuse(ip, 1.0);
+        }
+        refused(app_state, subjects, e)
+    })
+}
+
+fn charge(
+    app_state: &AppState,
+    subjects: &Subjects,
+    request: &LanguageModelPromptRequest,
+) -> Result<Reservation, ApplicationError> {
+    check_allowed(app_state, subjects)?;
+    if subjects.is_anonymous() && app_state.proof_of_work.applies_to(subjects.origin()) {
+        app_state
+            .proof_of_work
+            .spend(subjects.allowance.as_deref())?;
+    }
+    let provider = GeminiProvider::new(
+        app_state.gemini_client.clone(),
+        request.create_options.clone(),
+    );
+    let input_tokens = provider.count_tokens(&request.inputs)?;
+    Ok(app_state.limiter.reserve(subjects, input_tokens as u64)?)
+}
+
+/// Checks that the subjects may prompt the model, and hold a proof-of-work allowance when they
+/// need one, without charging them. Session messages that only keep prompts for later pass this
+/// before their images are decoded.
+pub fn check_allowed(app_state: &AppState, subjects: &Subjects) -> Result<(), ApplicationError> {
+    if !subjects.allows_model(GeminiProvider::model_id()) {
+        return Err(ApplicationError::Forbidden(
+            "The API key or client token may not use this model.",
+        ));
+    }
+    if subjects.is_anonymous() && app_state.proof_of_work.applies_to(subjects.origin()) {
+        app_state
+            .proof_of_work
+            .check(subjects.allowance.as_deref())?;
+    }
+    Ok(())
+}
+
+/// Records a prompt request refused before it reached the model in the ledger.
+pub fn refused(
+    app_state: &AppState,
+    subjects: &Subjects,
+    error: ApplicationError,
+) -> ApplicationError {
+    app_state
+        .ledger
+        .start(
+            subjects,
+            GeminiProvider::provider_id(),
+            GeminiProvider::model_id(),
+        )
+        .failed(error.code());
+    error
 }
 
 #[axum::debug_handler]
 pub async fn prompt_streaming(
     State(app_state): State<AppState>,
-    Json(request): Json<LanguageModelPromptRequest>,
-) -> impl IntoResponse {
+    client: ClientId,
+    subjects: Subjects,
+    Deadline(deadline): Deadline,
+    format: StreamFormat,
+    PromptBody(mut request, parts): PromptBody,
+) -> Result<impl IntoResponse, ApplicationError> {
     info!(request = ?request, "prompt streaming request");
+    request.check_limits()?;
+    prepare_create_options(&subjects, &mut request.create_options)?;
+    let reservation = reserve(&app_state, &subjects, &request)?;
+    let status = reservation.status();
+    request.load_media(parts, &app_state, &subjects).await?;
 
-    let (tx, rx) = mpsc::channel::<Result<String, Infallible>>(2);
-    tokio::spawn(stream_response(tx, app_state, request));
-    let body = Body::from_stream(ReceiverStream::new(rx));
+    let generation = app_state.generations.start(Owner::of(&subjects));
+    let generation_id = HeaderValue::from_str(&generation.id().to_string()).unwrap();
+    let events = generation.subscribe();
 
-    let headers = AppendHeaders([
-        (header::CONTENT_TYPE, "text/event-stream"),
-        (header::CACHE_CONTROL, "no-cache"),
-        (header::CONNECTION, "keep-alive"),
-    ]);
+    let (opened_tx, opened_rx) = oneshot::channel();
+    tokio::spawn(stream_response(
+        opened_tx,
+        generation,
+        app_state,
+        client,
+        request,
+        reservation,
+        deadline,
+    ));
 
-    (headers, body)
+    // Errors that happen before the stream opens are returned as a regular error response.
+    opened_rx.await.unwrap_or_else(|_| {
+        Err(AILanguageModelError::ProviderError(UpstreamError::new(
+            "The response stream closed before opening.",
+        )))
+    })?;
+
+    let mut response = format.into_response(events);
+    response
+        .headers_mut()
+        .insert(GENERATION_ID_HEADER, generation_id);
+    if let Some(status) = status {
+        status.add_headers(response.headers_mut());
+    }
+    Ok(response)
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResumeQuery {
+    resume_token: Option<String>,
+}
+
+/// Resumes a streaming generation after the event in the `Last-Event-ID` header, or in the
+/// `resumeToken` query parameter, replaying the events the client missed. Only the client that
+/// started the generation may resume it.
+#[axum::debug_handler]
+async fn resume_streaming(
+    State(app_state): State<AppState>,
+    subjects: Subjects,
+    format: StreamFormat,
+    headers: HeaderMap,
+    Query(query): Query<ResumeQuery>,
+) -> Result<impl IntoResponse, ApplicationError> {
+    let last_event_id = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|value| value.to_str().ok())
+        .or(query.resume_token.as_deref())
+        .ok_or(ApplicationError::InvalidResumeToken(
+            "Missing Last-Event-ID.",
+        ))?
+        .parse::<EventId>()
+        .map_err(ApplicationError::InvalidResumeToken)?;
+    info!("resume streaming request");
+
+    let events = app_state
+        .generations
+        .resume(&last_event_id, &Owner::of(&subjects))
+        .ok_or(ApplicationError::GenerationNotFound)?;
+
+    Ok(format.into_response(events))
+}
+
+// Generates the response, publishing its events to the generation. The upstream request is
+// dropped, which cancels it, when the generation is cancelled, when no client has followed it for
+// a while, or when the deadline passes. The generation holds its upstream admission until it ends,
+// settles its reservation with the usage reported last, however it ends, and records it in the
+// ledger when it ends.
+//
+// Failures are retried until the first chunk is published. After that, the client has seen part
+// of the response, and starting over would repeat it.
 pub async fn stream_response(
-    tx: Sender<Result<String, Infallible>>,
+    opened_tx: oneshot::Sender<Result<(), AILanguageModelError>>,
+    generation: Generation,
     app_state: AppState,
+    client: ClientId,
     request: LanguageModelPromptRequest,
+    reservation: Reservation,
+    deadline: Instant,
 ) {
     let provider = GeminiProvider::new(
         app_state.gemini_client.clone(),
         request.create_options.clone(),
     );
+    let retry_policy = app_state.retry_policy;
+    let breaker = app_state
+        .upstream
+        .breaker(GeminiProvider::provider_id(), GeminiProvider::model_id());
+    let mut entry = app_state.ledger.start(
+        reservation.subjects(),
+        GeminiProvider::provider_id(),
+        GeminiProvider::model_id(),
+    );
+
+    let opened = async {
+        let admission = app_state.upstream.admit(&client, deadline).await?;
+        let stream = with_deadline(
+            deadline,
+            retry_policy.retry(|| breaker.call(provider.prompt_streaming(&request.inputs))),
+        )
+        .await?;
+        Ok((admission, stream))
+    };
+    let (_admission, mut stream) = match opened.await {
+        Ok(opened) => {
+            let _ = opened_tx.send(Ok(()));
+            opened
+        }
+        Err(e) => {
+            error!("Gemini streaming request error: {}", e);
+            entry.failed(ErrorCode::from(&e));
+            let _ = opened_tx.send(Err(e));
+            return;
+        }
+    };
+
+    let mut delivered = Delivered::default();
+    let mut output = String::new();
+    let mut failed_attempts = 0;
+    let mut usage = None;
+    let mut safety_ratings = vec![];
+    // Kept across chunks, as a new future would start the wait for the clients over.
+    let abandoned = generation.abandoned();
+    tokio::pin!(abandoned);
+    let error = 'generation: loop {
+        let next = tokio::select! {
+            next = stream.next() => next,
+            _ = tokio::time::sleep_until(deadline) => break deadline_exceeded(),
+            _ = &mut abandoned => {
+                info!(delivered = ?delivered, "Generation abandoned by the client, aborted.");
+                settle_cut_short(reservation, usage.as_ref(), &output);
+                return;
+            }
+            _ = generation.cancelled() => {
+                info!(delivered = ?delivered, "Generation cancelled.");
+                settle_cut_short(reservation, usage.as_ref(), &output);
+                generation.publish(StreamEvent::Error(ErrorEvent::aborted(delivered)));
+                return;
+            }
+        };
 
-    let mut stream = provider.prompt_streaming(&request.inputs).await.unwrap();
-    while let Some(response) = stream.next().await {
-        let response = match response {
-            Ok(response) => response,
-            Err(e) => {
-                error!("Gemini streaming response error: {}", e);
-                break;
+        if let Some(Err(e)) = &next {
+            breaker.record_failure(e);
+        }
+
+        let response = match next {
+            Some(Ok(response)) => response,
+            Some(Err(mut e)) if delivered.chunks == 0 && e.is_retryable() => loop {
+                failed_attempts += 1;
+                let Some(backoff) = retry_policy.backoff(failed_attempts) else {
+                    break 'generation e;
+                };
+                warn!(generation = %generation.id(), attempt = failed_attempts, backoff = ?backoff, "Retrying streaming response: {}", e);
+                tokio::time::sleep_until((Instant::now() + backoff).min(deadline)).await;
+
+                let reopened = breaker.call(provider.prompt_streaming(&request.inputs));
+                match with_deadline(deadline, reopened).await {
+                    Ok(reopened) => {
+                        stream = reopened;
+                        continue 'generation;
+                    }
+                    Err(reopen_error) if reopen_error.is_retryable() => e = reopen_error,
+                    Err(reopen_error) => break 'generation reopen_error,
+                }
+            },
+            Some(Err(e)) => break e,
+            None => {
+                break AILanguageModelError::ProviderError(UpstreamError::new(
+                    "The response stream ended before the response was complete.",
+                ));
             }
         };
 
-        if let Some(response) = response.text {
-            let _ = tx.send(Ok(response)).await;
+        if let Some(text) = response.text {
+            delivered.add(&text);
+            output.push_str(&text);
+            generation.publish(StreamEvent::chunk(text));
+        }
+
+        if let Some(response_usage) = response.usage {
+            generation.publish(StreamEvent::Usage(response_usage));
+            entry.set_usage(response_usage);
+            usage = Some(response_usage);
+        }
+
+        // Gemini rates each chunk, the last rating covering the whole response.
+        if !response.safety_ratings.is_empty() {
+            safety_ratings = response.safety_ratings;
         }
 
         if response.finished {
-            break;
+            match &usage {
+                Some(usage) => reservation.settle(usage),
+                None => settle_cut_short(reservation, None, &output),
+            }
+            entry.succeeded();
+            let done = DoneEvent {
+                finish_reason: response.finish_reason,
+                block_reason: response.block_reason,
+                safety_ratings,
+            };
+            generation.publish(StreamEvent::Done(done));
+            return;
         }
+    };
+
+    error!(generation = %generation.id(), delivered = ?delivered, "Gemini streaming response error: {}", error);
+    entry.failed(ErrorCode::from(&error));
+    settle_cut_short(reservation, usage.as_ref(), &output);
+    let error = ErrorEvent::new(&error, delivered);
+    generation.publish(StreamEvent::Error(error));
+}
+
+// Settles the reservation of a generation that ended without the provider's final usage, counting
+// the output delivered with the tokenizer, so cancelling a stream just before its end doesn't
+// dodge the token budgets.
+fn settle_cut_short(reservation: Reservation, usage: Option<&AILanguageModelUsage>, output: &str) {
+    let delivered_tokens = tokenizer::count_tokens(output).unwrap_or_default();
+    reservation.settle_partial(usage, delivered_tokens as u32);
+}
+
+/// Cancels a streaming generation. Only the client that started the generation may cancel it.
+#[axum::debug_handler]
+async fn cancel_generation(
+    State(app_state): State<AppState>,
+    subjects: Subjects,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, ApplicationError> {
+    if app_state.generations.cancel(&id, &Owner::of(&subjects)) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApplicationError::GenerationNotFound)
     }
 }
 
@@ -112,9 +509,12 @@ async fn capabilities() -> impl IntoResponse {
 #[axum::debug_handler]
 async fn count_tokens(
     State(app_state): State<AppState>,
-    Json(request): Json<LanguageModelPromptRequest>,
-) -> Result<impl IntoResponse, ApplicationError> {
+    format: ResponseFormat,
+    // The binary parts only hold images and audio, whose contents the count doesn't need.
+    PromptBody(request, _): PromptBody,
+) -> Result<Response, ApplicationError> {
     info!(request = ?request, "count tokens request");
+    request.check_limits()?;
 
     let provider = GeminiProvider::new(
         app_state.gemini_client.clone(),
@@ -123,5 +523,19 @@ async fn count_tokens(
 
     let total_tokens = provider.count_tokens(&request.inputs)?;
 
-    Ok(total_tokens.to_string())
+    Ok(match format {
+        ResponseFormat::Json => Json(CountTokensResponse {
+            total_tokens,
+            model_id: GeminiProvider::model_id(),
+        })
+        .into_response(),
+        ResponseFormat::Text => total_tokens.to_string().into_response(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CountTokensResponse {
+    total_tokens: usize,
+    model_id: &'static str,
 }
@@ -1,11 +1,34 @@
 mod error;
+mod extract;
+mod images;
+mod multipart;
+mod negotiate;
+mod schema;
+mod stream;
 
+mod admin;
+mod challenge;
+mod client_token;
 mod language_model;
+mod session;
+
+pub use error::{ApplicationError, ErrorCode};
+pub use extract::{ALLOWANCE_HEADER, REQUEST_TIMEOUT_HEADER};
+pub use language_model::GENERATION_ID_HEADER;
+pub use stream::{StreamEvent, Streamed};
 
 use axum::Router;
 
 use crate::AppState;
 
 pub fn routes() -> Router<AppState> {
-    Router::new().nest("/language-model", language_model::routes())
+    Router::new()
+        .nest(
+            "/language-model",
+            language_model::routes()
+                .merge(session::routes())
+                .merge(client_token::routes())
+                .merge(challenge::routes()),
+        )
+        .nest("/admin", admin::routes())
 }
@@ -0,0 +1,425 @@
+use std::{collections::HashMap, io::SeekFrom};
+
+use axum::{
+    extract::{FromRequest, Request},
+    http::header,
+};
+use multer::{Constraints, Field, Multipart, SizeLimit};
+use serde_json::Value;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use built_in_hybrid_server::ai::language_model::AILanguageModelPrompt;
+
+use super::{
+    error::ApplicationError,
+    extract::AppJson,
+    language_model::LanguageModelPromptRequest,
+    schema::{InvalidBody, MAX_MEDIA_BYTES, from_json, from_value},
+};
+
+/// The name of the part holding the request's JSON.
+pub const REQUEST_PART: &str = "request";
+/// The largest `request` part, in bytes.
+pub const MAX_REQUEST_PART_BYTES: u64 = 2 * 1024 * 1024;
+/// The largest multipart body, in bytes: a binary part of the largest size, with room for the
+/// `request` part and smaller parts.
+pub const MAX_MULTIPART_BYTES: u64 = 24 * 1024 * 1024;
+// The most bytes of binary parts a request keeps in memory before it is charged. The parts past it
+// are spooled to temporary files.
+const SPOOL_BYTES: usize = 1024 * 1024;
+
+// The lists of prompts of a request, as JSON pointers.
+const INITIAL_PROMPTS: &str = "/createOptions/initialPrompts";
+const INPUTS: &str = "/inputs";
+
+/// A prompt request, sent either as JSON or as `multipart/form-data`.
+///
+/// A multipart request holds its JSON in the `request` part, and the content of its image and
+/// audio prompts in binary parts: a prompt refers to a part by name, with `"part": "<name>"`
+/// instead of `content`. Each part may be referred to once, and every part must be referred to.
+///
+/// The request is extracted before it is checked against the client's limits, so the binary parts
+/// are held apart, mostly on disk, and the prompts referring to them are left empty. The handler
+/// fills them with [`Parts::fill`] once the request is charged.
+#[derive(Debug)]
+pub struct PromptBody(pub LanguageModelPromptRequest, pub Parts);
+
+/// The binary parts of a multipart prompt request, with the prompts they belong to.
+#[derive(Debug, Default)]
+pub struct Parts(Vec<(Reference, Spooled)>);
+
+impl Parts {
+    /// Reads the binary parts into the prompts that refer to them.
+    pub async fn fill(
+        self,
+        request: &mut LanguageModelPromptRequest,
+    ) -> Result<(), ApplicationError> {
+        for (reference, part) in self.0 {
+            let prompts = match reference.list {
+                INITIAL_PROMPTS => &mut request.create_options.initial_prompts,
+                _ => &mut request.inputs,
+            };
+            if let AILanguageModelPrompt::Image { content, .. }
+            | AILanguageModelPrompt::Audio { content, .. } = &mut prompts[reference.index]
+            {
+                *content = part.into_bytes().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Send + Sync> FromRequest<S> for PromptBody {
+    type Rejection = ApplicationError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let boundary = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(|content_type| multer::parse_boundary(content_type).ok());
+        let Some(boundary) = boundary else {
+            let AppJson(request) = AppJson::from_request(req, state).await?;
+            return Ok(PromptBody(request, Parts::default()));
+        };
+
+        let constraints = Constraints::new().size_limit(
+            SizeLimit::new()
+                .whole_stream(MAX_MULTIPART_BYTES)
+                .per_field(MAX_MEDIA_BYTES as u64)
+                .for_field(REQUEST_PART, MAX_REQUEST_PART_BYTES),
+        );
+        let mut multipart =
+            Multipart::with_constraints(req.into_body().into_data_stream(), boundary, constraints);
+
+        let mut json = None;
+        let mut parts = HashMap::new();
+        let mut in_memory = 0;
+        while let Some(field) = multipart.next_field().await? {
+            let Some(name) = field.name().map(str::to_string) else {
+                return Err(InvalidBody::malformed("Every part must have a name.").into());
+            };
+            if (name == REQUEST_PART && json.is_some()) || parts.contains_key(&name) {
+                return Err(
+                    InvalidBody::malformed(format!("The part `{}` is repeated.", name)).into(),
+                );
+            }
+            if name == REQUEST_PART {
+                json = Some(field.bytes().await?);
+            } else {
+                parts.insert(name, Spooled::from_field(field, &mut in_memory).await?);
+            }
+        }
+        let json = json.ok_or_else(|| {
+            InvalidBody::malformed(format!("The `{}` part is missing.", REQUEST_PART))
+        })?;
+
+        let mut value: Value = from_json(&json)?;
+        let references = take_references(&mut value)?;
+        if let Some(name) = parts
+            .keys()
+            .find(|name| !references.iter().any(|reference| reference.part == **name))
+        {
+            return Err(InvalidBody::malformed(format!(
+                "No prompt refers to the part `{}`.",
+                name
+            ))
+            .into());
+        }
+
+        let request: LanguageModelPromptRequest = from_value(value)?;
+        let mut spooled = Vec::new();
+        for reference in references {
+            let Some(part) = parts.remove(&reference.part) else {
+                return Err(InvalidBody::new(
+                    format!("{}/{}/part", reference.list, reference.index),
+                    format!("No part is named `{}`.", reference.part),
+                )
+                .into());
+            };
+            spooled.push((reference, part));
+        }
+        Ok(PromptBody(request, Parts(spooled)))
+    }
+}
+
+// A prompt referring to a binary part.
+#[derive(Debug, PartialEq)]
+pub struct Reference {
+    list: &'static str,
+    index: usize,
+    part: String,
+}
+
+// Takes the part names out of the prompts of a request's JSON, leaving the prompts with an empty
+// content to be filled once the request is read.
+fn take_references(value: &mut Value) -> Result<Vec<Reference>, InvalidBody> {
+    let mut references: Vec<Reference> = Vec::new();
+    for list in [INITIAL_PROMPTS, INPUTS] {
+        let Some(Value::Array(prompts)) = value.pointer_mut(list) else {
+            continue;
+        };
+        for (index, prompt) in prompts.iter_mut().enumerate() {
+            let Some(prompt) = prompt.as_object_mut() else {
+                continue;
+            };
+            let Some(part) = prompt.remove("part") else {
+                continue;
+            };
+
+            let pointer = format!("{}/{}/part", list, index);
+            let Value::String(part) = part else {
+                return Err(InvalidBody::new(
+                    pointer,
+                    "The part must be named by a string.",
+                ));
+            };
+            if !matches!(
+                prompt.get("type").and_then(Value::as_str),
+                Some("image" | "audio")
+            ) {
+                return Err(InvalidBody::new(
+                    pointer,
+                    "Only image and audio prompts may refer to a part.",
+                ));
+            }
+            if prompt.contains_key("content") {
+                return Err(InvalidBody::new(
+                    pointer,
+                    "A prompt refers to a part or has a content, not both.",
+                ));
+            }
+            if references.iter().any(|reference| reference.part == part) {
+                return Err(InvalidBody::new(
+                    pointer,
+                    format!("The part `{}` is referred to more than once.", part),
+                ));
+            }
+
+            prompt.insert("content".to_string(), Value::String(String::new()));
+            references.push(Reference { list, index, part });
+        }
+    }
+
+    Ok(references)
+}
+
+// A binary part, kept in memory while the request's parts fit in `SPOOL_BYTES`, and spooled to an
+// unnamed temporary file, which is deleted once closed, past it.
+#[derive(Debug)]
+enum Spooled {
+    Memory(Vec<u8>),
+    File(File),
+}
+
+impl Spooled {
+    async fn from_field(
+        mut field: Field<'_>,
+        in_memory: &mut usize,
+    ) -> Result<Self, ApplicationError> {
+        let mut buffer = Vec::new();
+        let mut file: Option<File> = None;
+        while let Some(chunk) = field.chunk().await? {
+            match &mut file {
+                Some(file) => file.write_all(&chunk).await?,
+                None if *in_memory + chunk.len() > SPOOL_BYTES => {
+                    let temporary = tokio::task::spawn_blocking(tempfile::tempfile)
+                        .await
+                        .map_err(std::io::Error::other)??;
+                    let mut spooled = File::from_std(temporary);
+                    spooled.write_all(&buffer).await?;
+                    spooled.write_all(&chunk).await?;
+                    *in_memory -= buffer.len();
+                    buffer = Vec::new();
+                    file = Some(spooled);
+                }
+                None => {
+                    *in_memory += chunk.len();
+                    buffer.extend_from_slice(&chunk);
+                }
+            }
+        }
+
+        Ok(match file {
+            Some(mut file) => {
+                file.flush().await?;
+                Spooled::File(file)
+            }
+            None => Spooled::Memory(buffer),
+        })
+    }
+
+    // Reads the part back, once its request is charged, as the provider takes contents inline.
+    async fn into_bytes(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Spooled::Memory(bytes) => Ok(bytes),
+            Spooled::File(mut file) => {
+                file.seek(SeekFrom::Start(0)).await?;
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes).await?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+
+    use super::*;
+
+    const BOUNDARY: &str = "X-BOUNDARY";
+
+    fn multipart(parts: &[(&str, &[u8])]) -> Request {
+        let mut body = Vec::new();
+        for (name, content) in parts {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n",
+                    BOUNDARY, name
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(content);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+
+        Request::builder()
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn read(request: Request) -> Result<LanguageModelPromptRequest, ApplicationError> {
+        let PromptBody(mut request, parts) = PromptBody::from_request(request, &()).await?;
+        parts.fill(&mut request).await?;
+        Ok(request)
+    }
+
+    fn pointer(err: ApplicationError) -> String {
+        match err {
+            ApplicationError::InvalidBody(invalid) => invalid.pointer,
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn fills_prompts_from_binary_parts() {
+        let large = vec![7u8; SPOOL_BYTES + 10];
+        let request = read(multipart(&[
+            ("photo", b"\x89PNG"),
+            (
+                "request",
+                br#"{"createOptions":{"initialPrompts":[{"type":"audio","role":"user","part":"clip"}]},
+                "inputs":[{"type":"text","role":"user","content":"What is this?"},
+                {"type":"image","role":"user","part":"photo"}]}"#,
+            ),
+            ("clip", &large),
+        ]))
+        .await
+        .unwrap();
+
+        assert_eq!(
+            request.inputs[1],
+            AILanguageModelPrompt::Image {
+                role: Default::default(),
+                content: b"\x89PNG".to_vec(),
+            }
+        );
+        assert_eq!(
+            request.create_options.initial_prompts[0],
+            AILanguageModelPrompt::Audio {
+                role: Default::default(),
+                content: large,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn spools_parts_past_the_memory_budget() {
+        let half = vec![1u8; SPOOL_BYTES / 2 + 1];
+        let PromptBody(request, parts) = PromptBody::from_request(
+            multipart(&[
+                ("one", &half),
+                ("two", &half),
+                (
+                    "request",
+                    br#"{"inputs":[{"type":"audio","role":"user","part":"one"},
+                    {"type":"audio","role":"user","part":"two"}]}"#,
+                ),
+            ]),
+            &(),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            parts.0.as_slice(),
+            [(_, Spooled::Memory(_)), (_, Spooled::File(_))]
+        ));
+        assert!(matches!(
+            &request.inputs[1],
+            AILanguageModelPrompt::Audio { content, .. } if content.is_empty()
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_broken_references() {
+        let missing = read(multipart(&[(
+            "request",
+            br#"{"inputs":[{"type":"image","role":"user","part":"photo"}]}"#,
+        )]))
+        .await
+        .unwrap_err();
+        assert_eq!(pointer(missing), "/inputs/0/part");
+
+        let text = read(multipart(&[
+            ("note", b"Hi"),
+            (
+                "request",
+                br#"{"inputs":[{"type":"text","role":"user","part":"note"}]}"#,
+            ),
+        ]))
+        .await
+        .unwrap_err();
+        assert_eq!(pointer(text), "/inputs/0/part");
+
+        let unused = read(multipart(&[
+            ("photo", b"\x89PNG"),
+            ("request", br#"{"inputs":[]}"#),
+        ]))
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            unused,
+            ApplicationError::InvalidBody(InvalidBody {
+                malformed: true,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_parts_over_the_limit() {
+        let large = vec![0u8; MAX_MEDIA_BYTES + 1];
+        let err = read(multipart(&[
+            ("photo", &large),
+            (
+                "request",
+                br#"{"inputs":[{"type":"image","role":"user","part":"photo"}]}"#,
+            ),
+        ]))
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.status_code(), axum::http::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
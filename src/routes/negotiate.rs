@@ -0,0 +1,107 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::FromRequestParts,
+    http::{HeaderMap, header, request::Parts},
+};
+
+/// Whether the `Accept` header lists `media_type`, with a nonzero quality. Wildcards such as
+/// `*/*` or `application/*` don't count: the other formats are only for clients that name them,
+/// and everyone else gets the default.
+pub fn accepts(headers: &HeaderMap, media_type: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|accepted| {
+            let mut parts = accepted.split(';');
+            parts
+                .next()
+                .is_some_and(|accepted| accepted.trim().eq_ignore_ascii_case(media_type))
+                && quality(parts) > 0.0
+        })
+}
+
+// The `q` parameter among the parameters of an accepted media type, 1 when it is missing. An
+// invalid one counts as 0, leaving the client with the default.
+fn quality<'a>(mut parameters: impl Iterator<Item = &'a str>) -> f32 {
+    parameters
+        .find_map(|parameter| {
+            let (name, value) = parameter.split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case("q")
+                .then(|| value.trim().parse().unwrap_or(0.0))
+        })
+        .unwrap_or(1.0)
+}
+
+/// The format of a non-streaming response, negotiated from the `Accept` header.
+///
+/// Clients asking for `application/json` get a JSON envelope with the response metadata.
+/// Everyone else gets plain text, as before.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResponseFormat {
+    Json,
+    Text,
+}
+
+impl ResponseFormat {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        if accepts(headers, "application/json") {
+            ResponseFormat::Json
+        } else {
+            ResponseFormat::Text
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ResponseFormat {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ResponseFormat::from_headers(&parts.headers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_media_types_with_parameters() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT,
+            "text/plain;q=0.5, application/json;q=0.9".parse().unwrap(),
+        );
+
+        assert!(accepts(&headers, "application/json"));
+        assert!(!accepts(&headers, "application/x-ndjson"));
+        assert_eq!(ResponseFormat::from_headers(&headers), ResponseFormat::Json);
+    }
+
+    #[test]
+    fn defaults_to_text() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, "*/*".parse().unwrap());
+
+        assert_eq!(ResponseFormat::from_headers(&headers), ResponseFormat::Text);
+    }
+
+    #[test]
+    fn ignores_refused_and_wildcard_media_types() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT,
+            "application/json; q=0, application/*, text/event-stream;Q=0.000, */*;q=0.1"
+                .parse()
+                .unwrap(),
+        );
+
+        assert!(!accepts(&headers, "application/json"));
+        assert!(!accepts(&headers, "application/x-ndjson"));
+        assert!(!accepts(&headers, "text/event-stream"));
+        assert_eq!(ResponseFormat::from_headers(&headers), ResponseFormat::Text);
+    }
+}
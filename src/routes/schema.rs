@@ -0,0 +1,181 @@
+use std::fmt::Display;
+
+use serde::de::DeserializeOwned;
+use serde_json::{Value, error::Category};
+use serde_path_to_error::{Path, Segment};
+
+use built_in_hybrid_server::ai::language_model::AILanguageModelPrompt;
+
+/// The most prompts a list of prompts may hold.
+pub const MAX_PROMPTS: usize = 256;
+/// The longest text prompt, in bytes.
+pub const MAX_TEXT_BYTES: usize = 256 * 1024;
+/// The largest image or audio prompt, in bytes.
+pub const MAX_MEDIA_BYTES: usize = 20 * 1024 * 1024;
+
+/// A body that isn't JSON, or doesn't fit the request's schema or limits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidBody {
+    /// A JSON pointer to the offending value, empty for the whole body.
+    pub pointer: String,
+    pub message: String,
+    /// Whether the body isn't JSON at all, rather than JSON that doesn't fit.
+    pub malformed: bool,
+}
+
+impl InvalidBody {
+    pub fn new(pointer: String, message: impl Into<String>) -> Self {
+        InvalidBody {
+            pointer,
+            message: message.into(),
+            malformed: false,
+        }
+    }
+
+    /// A body whose structure is broken, rather than one holding an invalid value.
+    pub fn malformed(message: impl Into<String>) -> Self {
+        InvalidBody {
+            pointer: String::new(),
+            message: message.into(),
+            malformed: true,
+        }
+    }
+}
+
+impl Display for InvalidBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.pointer.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{} (at {})", self.message, self.pointer)
+        }
+    }
+}
+
+/// Reads a JSON body, keeping the path to the value that didn't fit.
+pub fn from_json<T: DeserializeOwned>(json: &[u8]) -> Result<T, InvalidBody> {
+    let mut deserializer = serde_json::Deserializer::from_slice(json);
+    let value = serde_path_to_error::deserialize(&mut deserializer).map_err(invalid)?;
+    deserializer.end().map_err(|e| InvalidBody {
+        pointer: String::new(),
+        message: e.to_string(),
+        malformed: true,
+    })?;
+    Ok(value)
+}
+
+/// Like [`from_json`], for a body that was already parsed.
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, InvalidBody> {
+    serde_path_to_error::deserialize(value).map_err(invalid)
+}
+
+fn invalid(e: serde_path_to_error::Error<serde_json::Error>) -> InvalidBody {
+    InvalidBody {
+        pointer: pointer(e.path()),
+        message: e.inner().to_string(),
+        malformed: !matches!(e.inner().classify(), Category::Data),
+    }
+}
+
+/// Checks the number of prompts in a list, and the size of each, `pointer` pointing at the list.
+pub fn check_prompts(pointer: &str, prompts: &[AILanguageModelPrompt]) -> Result<(), InvalidBody> {
+    if prompts.len() > MAX_PROMPTS {
+        return Err(InvalidBody::new(
+            pointer.to_string(),
+            format!("At most {} prompts are allowed.", MAX_PROMPTS),
+        ));
+    }
+
+    for (index, prompt) in prompts.iter().enumerate() {
+        let (size, max_size) = match prompt {
+            AILanguageModelPrompt::Text { content, .. } => (content.len(), MAX_TEXT_BYTES),
+            AILanguageModelPrompt::Image { content, .. }
+            | AILanguageModelPrompt::Audio { content, .. } => (content.len(), MAX_MEDIA_BYTES),
+        };
+        if size > max_size {
+            return Err(InvalidBody::new(
+                format!("{}/{}/content", pointer, index),
+                format!(
+                    "The content is {} bytes, over the limit of {}.",
+                    size, max_size
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Serde's path, as a JSON pointer (RFC 6901).
+fn pointer(path: &Path) -> String {
+    path.iter()
+        .filter_map(|segment| match segment {
+            Segment::Seq { index } => Some(index.to_string()),
+            Segment::Map { key } => Some(key.replace('~', "~0").replace('/', "~1")),
+            Segment::Enum { .. } | Segment::Unknown => None,
+        })
+        .map(|token| format!("/{}", token))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct Request {
+        inputs: Vec<AILanguageModelPrompt>,
+    }
+
+    #[test]
+    fn points_at_the_invalid_field() {
+        let err = from_json::<Request>(
+            br#"{"inputs":[{"type":"text","role":"user","content":"Hi"},
+            {"type":"text","role":"user","content":3}]}"#,
+        )
+        .unwrap_err();
+
+        assert_eq!(err.pointer, "/inputs/1/content");
+        assert!(!err.malformed);
+    }
+
+    #[test]
+    fn refuses_unknown_fields_and_malformed_json() {
+        let err = from_json::<Request>(br#"{"inputs":[],"input":[]}"#).unwrap_err();
+        assert!(err.message.contains("unknown field `input`"), "{}", err);
+
+        let err = from_json::<Request>(br#"{"inputs":["#).unwrap_err();
+        assert!(err.malformed);
+    }
+
+    #[test]
+    fn limits_prompts() {
+        let prompt = |content: &str| AILanguageModelPrompt::Text {
+            role: Default::default(),
+            content: content.to_string(),
+            prefix: false,
+        };
+
+        let too_many = vec![prompt("Hi"); MAX_PROMPTS + 1];
+        assert_eq!(
+            check_prompts("/inputs", &too_many).unwrap_err().pointer,
+            "/inputs"
+        );
+
+        let too_long = vec![prompt("Hi"), prompt(&"a".repeat(MAX_TEXT_BYTES + 1))];
+        assert_eq!(
+            check_prompts("/inputs", &too_long).unwrap_err().pointer,
+            "/inputs/1/content"
+        );
+    }
+
+    #[test]
+    fn escapes_pointer_tokens() {
+        let err =
+            from_json::<std::collections::HashMap<String, u32>>(br#"{"a/b~c":"x"}"#).unwrap_err();
+        assert_eq!(err.pointer, "/a~1b~0c");
+    }
+}
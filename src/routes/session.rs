@@ -0,0 +1,842 @@
+use std::collections::HashMap;
+
+use axum::{
+    Router,
+    extract::{
+        State, WebSocketUpgrade,
+        ws::{Message, WebSocket},
+    },
+    response::IntoResponse,
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    mpsc::{self, Sender},
+    oneshot,
+};
+use tokio_stream::{StreamExt, wrappers::ReceiverStream};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    client_tokens::TokenError,
+    generations::{EventId, Owner},
+    ledger::unix_now,
+    limits::Subjects,
+    upstream::ClientId,
+};
+use built_in_hybrid_server::ai::language_model::{
+    AILanguageModelCreateOptions, AILanguageModelError, AILanguageModelPrompt,
+    AILanguageModelPromptRole, AILanguageModelUsage, UpstreamError,
+};
+
+use super::{
+    error::{ApplicationError, ErrorCode},
+    extract::Deadline,
+    images::normalize_images,
+    language_model::{
+        LanguageModelPromptRequest, check_allowed, prepare_create_options, refused, reserve,
+        stream_response,
+    },
+    schema::{InvalidBody, check_prompts, from_json},
+    stream::{Delivered, DoneEvent, ErrorEvent, StreamEvent},
+};
+
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/session", get(session))
+}
+
+type SessionId = u64;
+
+/// The most sessions a connection may hold at once, clones included.
+const MAX_SESSIONS: usize = 16;
+
+/// Messages sent by the client over the session WebSocket.
+#[derive(Debug, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase",
+    deny_unknown_fields
+)]
+enum ClientMessage {
+    Create {
+        create_options: Box<AILanguageModelCreateOptions>,
+    },
+    Prompt {
+        session: SessionId,
+        inputs: Vec<AILanguageModelPrompt>,
+    },
+    Append {
+        session: SessionId,
+        inputs: Vec<AILanguageModelPrompt>,
+    },
+    Abort {
+        session: SessionId,
+    },
+    Clone {
+        session: SessionId,
+    },
+    Destroy {
+        session: SessionId,
+    },
+}
+
+impl ClientMessage {
+    // Reads a message, checking its prompts. Their images are normalized once the message is
+    // handled.
+    fn read(text: &str) -> Result<Self, InvalidBody> {
+        let message: ClientMessage = from_json(text.as_bytes())?;
+        let (list, prompts) = match &message {
+            ClientMessage::Create { create_options } => (
+                "/createOptions/initialPrompts",
+                &create_options.initial_prompts,
+            ),
+            ClientMessage::Prompt { inputs, .. } | ClientMessage::Append { inputs, .. } => {
+                ("/inputs", inputs)
+            }
+            _ => return Ok(message),
+        };
+        check_prompts(list, prompts)?;
+        Ok(message)
+    }
+}
+
+// Checks the credentials the connection was opened with still work. A connection outlives them,
+// so an expired client token, or a revoked or rotated API key, stops working on the next message.
+fn check_credentials(app_state: &AppState, subjects: &Subjects) -> Result<(), ApplicationError> {
+    if let Some(key) = &subjects.api_key
+        && !app_state.api_keys.is_current(key)
+    {
+        return Err(ApplicationError::InvalidApiKey);
+    }
+    if let Some(token) = &subjects.client_token {
+        if token.expires_at <= unix_now() {
+            return Err(ApplicationError::InvalidClientToken(TokenError::Expired));
+        }
+        if app_state.api_keys.get(&token.key).is_none() {
+            return Err(ApplicationError::InvalidClientToken(TokenError::KeyRevoked));
+        }
+    }
+    Ok(())
+}
+
+// Prompts a session keeps for later: the initial prompts it is created with, or inputs appended
+// to its history.
+enum Kept {
+    InitialPrompts(Box<AILanguageModelCreateOptions>),
+    Inputs(Vec<AILanguageModelPrompt>),
+}
+
+impl Kept {
+    fn prompts(&mut self) -> (&'static str, &mut [AILanguageModelPrompt]) {
+        match self {
+            Kept::InitialPrompts(create_options) => (
+                "/createOptions/initialPrompts",
+                &mut create_options.initial_prompts,
+            ),
+            Kept::Inputs(inputs) => ("/inputs", inputs),
+        }
+    }
+}
+
+// Normalizes the images of prompts a session keeps, on a task of its own so the connection keeps
+// handling messages while they decode, and hands them back through `outgoing`. Nothing is charged
+// until they are prompted, so the client must at least be allowed to prompt the model before they
+// are decoded. Without images, they are handed back right away.
+fn normalize_kept_images(
+    app_state: &AppState,
+    subjects: &Subjects,
+    session: SessionId,
+    mut kept: Kept,
+    outgoing: &Sender<Outgoing>,
+) -> Result<Option<Kept>, ApplicationError> {
+    if !kept
+        .prompts()
+        .1
+        .iter()
+        .any(|prompt| matches!(prompt, AILanguageModelPrompt::Image { .. }))
+    {
+        return Ok(Some(kept));
+    }
+    check_allowed(app_state, subjects)?;
+
+    let outgoing = outgoing.clone();
+    tokio::spawn(async move {
+        let (list, prompts) = kept.prompts();
+        let normalized = normalize_images(vec![(list, prompts)])
+            .await
+            .map_err(ApplicationError::from);
+        let _ = outgoing
+            .send(Outgoing::Kept {
+                session,
+                kept,
+                normalized,
+            })
+            .await;
+    });
+    Ok(None)
+}
+
+/// Messages sent by the server over the session WebSocket.
+#[derive(Debug, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+enum ServerMessage {
+    Created {
+        session: SessionId,
+    },
+    Appended {
+        session: SessionId,
+    },
+    Cloned {
+        session: SessionId,
+        clone: SessionId,
+    },
+    Destroyed {
+        session: SessionId,
+    },
+    Chunk {
+        session: SessionId,
+        text: String,
+    },
+    Usage {
+        session: SessionId,
+        #[serde(flatten)]
+        usage: AILanguageModelUsage,
+    },
+    Done {
+        session: SessionId,
+        #[serde(flatten)]
+        done: DoneEvent,
+    },
+    Error {
+        session: Option<SessionId>,
+        #[serde(flatten)]
+        error: ErrorEvent,
+    },
+}
+
+impl ServerMessage {
+    fn from_event(session: SessionId, event: StreamEvent) -> Self {
+        match event {
+            StreamEvent::Chunk(chunk) => ServerMessage::Chunk {
+                session,
+                text: chunk.text,
+            },
+            StreamEvent::Usage(usage) => ServerMessage::Usage { session, usage },
+            StreamEvent::Error(error) => ServerMessage::Error {
+                session: Some(session),
+                error,
+            },
+            StreamEvent::Done(done) => ServerMessage::Done { session, done },
+        }
+    }
+
+    fn error(session: Option<SessionId>, code: ErrorCode, message: &str) -> Self {
+        ServerMessage::Error {
+            session,
+            error: ErrorEvent {
+                code,
+                name: code.exception_name(),
+                message: message.to_string(),
+                retryable: false,
+                delivered: Delivered::default(),
+            },
+        }
+    }
+}
+
+// Work handed back to the connection loop by the tasks running prompts.
+enum Outgoing {
+    Message(ServerMessage),
+    // A prompt finished. Its inputs and response are appended to the history when it completed.
+    Finished {
+        session: SessionId,
+        completion: Option<(Vec<AILanguageModelPrompt>, String)>,
+    },
+    // The images of prompts a session keeps were normalized, or failed to.
+    Kept {
+        session: SessionId,
+        kept: Kept,
+        normalized: Result<(), ApplicationError>,
+    },
+}
+
+// A language model session, kept for the lifetime of the connection.
+#[derive(Debug, Clone)]
+struct Session {
+    create_options: AILanguageModelCreateOptions,
+    history: Vec<AILanguageModelPrompt>,
+    // The generation answering the current prompt, if any.
+    running: Option<Uuid>,
+    // Whether the images of prompts it keeps are being normalized. They are kept, in order, once
+    // they are.
+    normalizing: bool,
+}
+
+impl Session {
+    // Why the session can't take inputs yet, if it can't.
+    fn busy(&self) -> Option<&'static str> {
+        if self.running.is_some() {
+            Some("The session is already answering a prompt.")
+        } else if self.normalizing {
+            Some("The session is still appending inputs.")
+        } else {
+            None
+        }
+    }
+
+    // Keeps prompts whose images were normalized, acknowledging them.
+    fn keep(&mut self, session: SessionId, kept: Kept) -> ServerMessage {
+        self.normalizing = false;
+        match kept {
+            Kept::InitialPrompts(create_options) => {
+                self.create_options = *create_options;
+                ServerMessage::Created { session }
+            }
+            Kept::Inputs(inputs) => {
+                self.history.extend(inputs);
+                ServerMessage::Appended { session }
+            }
+        }
+    }
+
+    // The history followed by `inputs`, as long as it stays within the limits on prompts.
+    fn extended(
+        &self,
+        inputs: Vec<AILanguageModelPrompt>,
+    ) -> Result<Vec<AILanguageModelPrompt>, InvalidBody> {
+        let prompts: Vec<_> = self.history.iter().cloned().chain(inputs).collect();
+        check_prompts("/inputs", &prompts)?;
+        Ok(prompts)
+    }
+
+    fn complete(&mut self, mut inputs: Vec<AILanguageModelPrompt>, response: String) {
+        // The response continues an assistant prefix, so both become a single assistant turn.
+        let content = match inputs.pop_if(|input| input.is_prefix()) {
+            Some(AILanguageModelPrompt::Text { content, .. }) => content + &response,
+            _ => response,
+        };
+
+        self.history.extend(inputs);
+        self.history.push(AILanguageModelPrompt::Text {
+            role: AILanguageModelPromptRole::Assistant,
+            content,
+            prefix: false,
+        });
+    }
+}
+
+#[axum::debug_handler]
+async fn session(
+    State(app_state): State<AppState>,
+    client: ClientId,
+    subjects: Subjects,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(|socket| handle_socket(socket, app_state, client, subjects))
+}
+
+// Runs the sessions of a single connection. Prompts run on their own tasks and report back
+// through `outgoing`, so the connection keeps reading messages, including aborts, while they run.
+async fn handle_socket(
+    mut socket: WebSocket,
+    app_state: AppState,
+    client: ClientId,
+    subjects: Subjects,
+) {
+    let mut sessions: HashMap<SessionId, Session> = HashMap::new();
+    let mut next_session_id: SessionId = 1;
+    let (outgoing_tx, mut outgoing_rx) = mpsc::channel::<Outgoing>(16);
+
+    loop {
+        let message = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    match ClientMessage::read(text.as_str()) {
+                        Ok(message) => handle_message(
+                            message,
+                            &mut sessions,
+                            &mut next_session_id,
+                            &app_state,
+                            &client,
+                            &subjects,
+                            &outgoing_tx,
+                        ).await,
+                        Err(e) => Some(ServerMessage::error(None, ErrorCode::InvalidRequest, &e.to_string())),
+                    }
+                }
+                Some(Ok(Message::Binary(_))) => Some(ServerMessage::error(
+                    None,
+                    ErrorCode::UnsupportedInput,
+                    "Binary messages are not supported.",
+                )),
+                Some(Ok(_)) => None,
+                Some(Err(_)) | None => break,
+            },
+            Some(outgoing) = outgoing_rx.recv() => match outgoing {
+                Outgoing::Message(message) => Some(message),
+                Outgoing::Finished { session, completion } => {
+                    if let Some(session) = sessions.get_mut(&session) {
+                        session.running = None;
+                        if let Some((inputs, response)) = completion {
+                            session.complete(inputs, response);
+                        }
+                    }
+                    None
+                }
+                Outgoing::Kept { session: session_id, kept, normalized } => match normalized {
+                    // The session may have been destroyed meanwhile.
+                    Ok(()) => sessions
+                        .get_mut(&session_id)
+                        .map(|session| session.keep(session_id, kept)),
+                    // A session isn't created without its initial prompts.
+                    Err(e) => match kept {
+                        Kept::InitialPrompts(_) => sessions
+                            .remove(&session_id)
+                            .map(|_| ServerMessage::error(None, e.code(), &e.to_string())),
+                        Kept::Inputs(_) => sessions.get_mut(&session_id).map(|session| {
+                            session.normalizing = false;
+                            ServerMessage::error(Some(session_id), e.code(), &e.to_string())
+                        }),
+                    },
+                },
+            },
+        };
+
+        let Some(message) = message else {
+            continue;
+        };
+
+        let text = serde_json::to_string(&message).expect("Server messages serialize to JSON");
+        if socket.send(Message::Text(text.into())).await.is_err() {
+            break;
+        }
+    }
+
+    // Cancel the prompts still running, rather than waiting for them to notice the connection is
+    // gone.
+    let owner = Owner::of(&subjects);
+    for generation in sessions.values().filter_map(|session| session.running) {
+        app_state.generations.cancel(&generation, &owner);
+    }
+    info!(sessions = sessions.len(), "Session connection closed.");
+}
+
+async fn handle_message(
+    message: ClientMessage,
+    sessions: &mut HashMap<SessionId, Session>,
+    next_session_id: &mut SessionId,
+    app_state: &AppState,
+    client: &ClientId,
+    subjects: &Subjects,
+    outgoing: &Sender<Outgoing>,
+) -> Option<ServerMessage> {
+    let unknown_session =
+        |session| ServerMessage::error(Some(session), ErrorCode::InvalidState, "Unknown session.");
+    let too_many_sessions = |session| {
+        ServerMessage::error(
+            session,
+            ErrorCode::InvalidState,
+            &format!("A connection may hold at most {} sessions.", MAX_SESSIONS),
+        )
+    };
+
+    // The messages that use the model, and the session they concern. The others only manage what
+    // the connection already holds.
+    let uses_model = match &message {
+        ClientMessage::Create { .. } => Some(None),
+        ClientMessage::Prompt { session, .. } | ClientMessage::Append { session, .. } => {
+            Some(Some(*session))
+        }
+        _ => None,
+    };
+    if let Some(session) = uses_model
+        && let Err(e) = check_credentials(app_state, subjects)
+    {
+        info!("Session credentials no longer valid: {}", e);
+        return Some(ServerMessage::error(session, e.code(), &e.to_string()));
+    }
+
+    match message {
+        ClientMessage::Create { mut create_options } => {
+            if sessions.len() >= MAX_SESSIONS {
+                return Some(too_many_sessions(None));
+            }
+            if let Err(e) = prepare_create_options(subjects, &mut create_options) {
+                return Some(ServerMessage::error(None, e.code(), &e.to_string()));
+            }
+            let session_id = *next_session_id;
+            let kept = match normalize_kept_images(
+                app_state,
+                subjects,
+                session_id,
+                Kept::InitialPrompts(create_options),
+                outgoing,
+            ) {
+                Ok(kept) => kept,
+                Err(e) => return Some(ServerMessage::error(None, e.code(), &e.to_string())),
+            };
+            *next_session_id += 1;
+            // The create options are set once the initial prompts are kept.
+            let mut session = Session {
+                create_options: AILanguageModelCreateOptions::default(),
+                history: vec![],
+                running: None,
+                normalizing: true,
+            };
+            let created = kept.map(|kept| session.keep(session_id, kept));
+            sessions.insert(session_id, session);
+            created
+        }
+        ClientMessage::Prompt {
+            session: session_id,
+            inputs,
+        } => {
+            let Some(session) = sessions.get_mut(&session_id) else {
+                return Some(unknown_session(session_id));
+            };
+            if let Some(busy) = session.busy() {
+                return Some(ServerMessage::error(
+                    Some(session_id),
+                    ErrorCode::InvalidState,
+                    busy,
+                ));
+            }
+
+            let history_len = session.history.len();
+            let mut request = match session.extended(inputs) {
+                Ok(prompts) => LanguageModelPromptRequest {
+                    create_options: session.create_options.clone(),
+                    inputs: prompts,
+                },
+                Err(e) => {
+                    return Some(ServerMessage::error(
+                        Some(session_id),
+                        ErrorCode::InvalidRequest,
+                        &e.to_string(),
+                    ));
+                }
+            };
+            let reservation = match reserve(app_state, subjects, &request) {
+                Ok(reservation) => reservation,
+                Err(e) => {
+                    return Some(ServerMessage::error(
+                        Some(session_id),
+                        e.code(),
+                        &e.to_string(),
+                    ));
+                }
+            };
+            let generation = app_state.generations.start(Owner::of(subjects));
+            session.running = Some(generation.id());
+            let deadline = Deadline::after(app_state.request_timeout).0;
+            let (app_state, subjects, client, outgoing) = (
+                app_state.clone(),
+                subjects.clone(),
+                client.clone(),
+                outgoing.clone(),
+            );
+            // The inputs' images are decoded off the connection loop too, so aborts still go through.
+            tokio::spawn(async move {
+                // The history's images were normalized when it was kept, so only the new inputs
+                // are.
+                if let Err(e) =
+                    normalize_images(vec![("/inputs", &mut request.inputs[history_len..])])
+                        .await
+                        .map_err(|e| refused(&app_state, &subjects, e.into()))
+                {
+                    let _ = outgoing
+                        .send(Outgoing::Finished {
+                            session: session_id,
+                            completion: None,
+                        })
+                        .await;
+                    let _ = outgoing
+                        .send(Outgoing::Message(ServerMessage::error(
+                            Some(session_id),
+                            e.code(),
+                            &e.to_string(),
+                        )))
+                        .await;
+                    return;
+                }
+                let inputs = request.inputs[history_len..].to_vec();
+
+                let events = generation.subscribe();
+                let (opened_tx, opened_rx) = oneshot::channel();
+                tokio::spawn(stream_response(
+                    opened_tx,
+                    generation,
+                    app_state,
+                    client,
+                    request,
+                    reservation,
+                    deadline,
+                ));
+                forward_events(session_id, inputs, opened_rx, events, outgoing).await;
+            });
+            None
+        }
+        ClientMessage::Append {
+            session: session_id,
+            inputs,
+        } => {
+            let Some(session) = sessions.get_mut(&session_id) else {
+                return Some(unknown_session(session_id));
+            };
+            // Appending while a prompt runs would put the inputs before its turn in the history.
+            if let Some(busy) = session.busy() {
+                return Some(ServerMessage::error(
+                    Some(session_id),
+                    ErrorCode::InvalidState,
+                    busy,
+                ));
+            }
+            if let Err(e) = session.extended(inputs.clone()) {
+                return Some(ServerMessage::error(
+                    Some(session_id),
+                    ErrorCode::InvalidRequest,
+                    &e.to_string(),
+                ));
+            }
+            match normalize_kept_images(
+                app_state,
+                subjects,
+                session_id,
+                Kept::Inputs(inputs),
+                outgoing,
+            ) {
+                Ok(Some(kept)) => Some(session.keep(session_id, kept)),
+                Ok(None) => {
+                    session.normalizing = true;
+                    None
+                }
+                Err(e) => Some(ServerMessage::error(
+                    Some(session_id),
+                    e.code(),
+                    &e.to_string(),
+                )),
+            }
+        }
+        ClientMessage::Abort {
+            session: session_id,
+        } => {
+            let Some(session) = sessions.get(&session_id) else {
+                return Some(unknown_session(session_id));
+            };
+            if let Some(generation) = session.running {
+                app_state
+                    .generations
+                    .cancel(&generation, &Owner::of(subjects));
+            }
+            None
+        }
+        ClientMessage::Clone {
+            session: session_id,
+        } => {
+            let Some(session) = sessions.get(&session_id) else {
+                return Some(unknown_session(session_id));
+            };
+            if session.normalizing {
+                return Some(ServerMessage::error(
+                    Some(session_id),
+                    ErrorCode::InvalidState,
+                    "The session is still appending inputs.",
+                ));
+            }
+            if sessions.len() >= MAX_SESSIONS {
+                return Some(too_many_sessions(Some(session_id)));
+            }
+            let clone = Session {
+                running: None,
+                ..session.clone()
+            };
+            let clone_id = *next_session_id;
+            *next_session_id += 1;
+            sessions.insert(clone_id, clone);
+            Some(ServerMessage::Cloned {
+                session: session_id,
+                clone: clone_id,
+            })
+        }
+        ClientMessage::Destroy {
+            session: session_id,
+        } => {
+            let Some(session) = sessions.remove(&session_id) else {
+                return Some(unknown_session(session_id));
+            };
+            if let Some(generation) = session.running {
+                app_state
+                    .generations
+                    .cancel(&generation, &Owner::of(subjects));
+            }
+            Some(ServerMessage::Destroyed {
+                session: session_id,
+            })
+        }
+    }
+}
+
+// Forwards the events of a prompt to the connection. The session is told the prompt finished
+// before its last event goes out, so a prompt sent in reply to `done` sees the updated history.
+async fn forward_events(
+    session: SessionId,
+    inputs: Vec<AILanguageModelPrompt>,
+    opened_rx: oneshot::Receiver<Result<(), AILanguageModelError>>,
+    mut events: ReceiverStream<(EventId, StreamEvent)>,
+    outgoing: Sender<Outgoing>,
+) {
+    let opened = opened_rx.await.unwrap_or_else(|_| {
+        Err(AILanguageModelError::ProviderError(UpstreamError::new(
+            "The response stream closed before opening.",
+        )))
+    });
+
+    let mut response = String::new();
+    let last_event = match opened {
+        Ok(()) => loop {
+            match events.next().await.map(|(_, event)| event) {
+                Some(StreamEvent::Chunk(chunk)) => {
+                    response.push_str(&chunk.text);
+                    let message = ServerMessage::from_event(session, StreamEvent::Chunk(chunk));
+                    if outgoing.send(Outgoing::Message(message)).await.is_err() {
+                        return;
+                    }
+                }
+                Some(StreamEvent::Usage(usage)) => {
+                    let message = ServerMessage::from_event(session, StreamEvent::Usage(usage));
+                    if outgoing.send(Outgoing::Message(message)).await.is_err() {
+                        return;
+                    }
+                }
+                Some(event) => break event,
+                None => {
+                    warn!(session, "Prompt stream closed without a final event.");
+                    let error = AILanguageModelError::ProviderError(UpstreamError::new(
+                        "The response stream ended before the response was complete.",
+                    ));
+                    break StreamEvent::Error(ErrorEvent::new(&error, Delivered::default()));
+                }
+            }
+        },
+        Err(e) => StreamEvent::Error(ErrorEvent::new(&e, Delivered::default())),
+    };
+
+    let completion = matches!(last_event, StreamEvent::Done(_)).then_some((inputs, response));
+    let _ = outgoing
+        .send(Outgoing::Finished {
+            session,
+            completion,
+        })
+        .await;
+    let _ = outgoing
+        .send(Outgoing::Message(ServerMessage::from_event(
+            session, last_event,
+        )))
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::schema::MAX_PROMPTS;
+    use super::*;
+
+    fn text(role: AILanguageModelPromptRole, content: &str, prefix: bool) -> AILanguageModelPrompt {
+        AILanguageModelPrompt::Text {
+            role,
+            content: content.to_string(),
+            prefix,
+        }
+    }
+
+    #[test]
+    fn deserializes_client_messages() {
+        let message: ClientMessage = serde_json::from_str(
+            r#"{"type":"prompt","session":1,"inputs":[{"type":"text","role":"user","content":"Hi"}]}"#,
+        )
+        .unwrap();
+
+        assert!(matches!(message, ClientMessage::Prompt { session: 1, .. }));
+    }
+
+    #[test]
+    fn serializes_stream_events_with_session() {
+        let message = ServerMessage::from_event(3, StreamEvent::chunk("Hi".to_string()));
+
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"type":"chunk","session":3,"text":"Hi"}"#
+        );
+    }
+
+    #[test]
+    fn completing_a_prefix_prompt_merges_the_assistant_turn() {
+        let mut session = Session {
+            create_options: AILanguageModelCreateOptions::default(),
+            history: vec![],
+            running: None,
+            normalizing: false,
+        };
+
+        session.complete(
+            vec![
+                text(AILanguageModelPromptRole::User, "Count to three.", false),
+                text(AILanguageModelPromptRole::Assistant, "1,", true),
+            ],
+            " 2, 3".to_string(),
+        );
+
+        assert_eq!(
+            session.history,
+            vec![
+                text(AILanguageModelPromptRole::User, "Count to three.", false),
+                text(AILanguageModelPromptRole::Assistant, "1, 2, 3", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn limits_the_prompts_of_the_history() {
+        let mut session = Session {
+            create_options: AILanguageModelCreateOptions::default(),
+            history: vec![],
+            running: None,
+            normalizing: false,
+        };
+        let hi = || text(AILanguageModelPromptRole::User, "Hi", false);
+
+        session.history = session.extended(vec![hi(); MAX_PROMPTS - 1]).unwrap();
+        session.history = session.extended(vec![hi()]).unwrap();
+        assert_eq!(session.extended(vec![hi()]).unwrap_err().pointer, "/inputs");
+    }
+
+    #[test]
+    fn takes_no_inputs_until_kept_prompts_are_in_order() {
+        let mut session = Session {
+            create_options: AILanguageModelCreateOptions::default(),
+            history: vec![],
+            running: Some(Uuid::new_v4()),
+            normalizing: false,
+        };
+        assert_eq!(
+            session.busy(),
+            Some("The session is already answering a prompt.")
+        );
+
+        session.running = None;
+        session.normalizing = true;
+        assert!(session.busy().is_some());
+
+        let hi = text(AILanguageModelPromptRole::User, "Hi", false);
+        let message = session.keep(1, Kept::Inputs(vec![hi.clone()]));
+        assert!(matches!(message, ServerMessage::Appended { session: 1 }));
+        assert_eq!(session.busy(), None);
+        assert_eq!(session.history, vec![hi]);
+    }
+}
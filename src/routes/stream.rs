@@ -0,0 +1,308 @@
+use std::{convert::Infallible, io};
+
+use axum::{
+    body::Body,
+    extract::FromRequestParts,
+    http::{HeaderMap, header, request::Parts},
+    response::{
+        AppendHeaders, IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use serde::Serialize;
+use tokio_stream::{Stream, StreamExt};
+
+use super::{
+    error::{ErrorCode, masked_message},
+    negotiate::accepts,
+};
+use crate::generations::EventId;
+use built_in_hybrid_server::ai::language_model::{
+    AILanguageModelError, AILanguageModelSafetyRating, AILanguageModelUsage,
+};
+
+const EVENT_STREAM: &str = "text/event-stream";
+const NDJSON: &str = "application/x-ndjson";
+
+/// An event produced while streaming a response to the client.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum StreamEvent {
+    Chunk(ChunkEvent),
+    Usage(AILanguageModelUsage),
+    Error(ErrorEvent),
+    Done(DoneEvent),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkEvent {
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorEvent {
+    pub code: ErrorCode,
+    /// The name of the `DOMException` the client should reject with.
+    pub name: &'static str,
+    pub message: String,
+    pub retryable: bool,
+    /// How much of the response was delivered before the error.
+    pub delivered: Delivered,
+}
+
+impl ErrorEvent {
+    pub fn new(error: &AILanguageModelError, delivered: Delivered) -> Self {
+        let code = ErrorCode::from(error);
+        ErrorEvent {
+            code,
+            name: code.exception_name(),
+            message: masked_message(error).map_or_else(|| error.to_string(), str::to_string),
+            retryable: error.is_retryable(),
+            delivered,
+        }
+    }
+
+    pub fn aborted(delivered: Delivered) -> Self {
+        ErrorEvent {
+            code: ErrorCode::Aborted,
+            name: ErrorCode::Aborted.exception_name(),
+            message: "The generation was cancelled.".to_string(),
+            retryable: false,
+            delivered,
+        }
+    }
+}
+
+/// Tracks the output sent to the client so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Delivered {
+    pub chunks: usize,
+    pub characters: usize,
+}
+
+impl Delivered {
+    pub fn add(&mut self, text: &str) {
+        self.chunks += 1;
+        self.characters += text.chars().count();
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DoneEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+    /// Why the safety filters stopped the response, if they did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_reason: Option<String>,
+    /// The last safety ratings the provider gave the response.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub safety_ratings: Vec<AILanguageModelSafetyRating>,
+}
+
+impl StreamEvent {
+    pub fn chunk(text: String) -> Self {
+        StreamEvent::Chunk(ChunkEvent { text })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamEvent::Chunk(_) => "chunk",
+            StreamEvent::Usage(_) => "usage",
+            StreamEvent::Error(_) => "error",
+            StreamEvent::Done(_) => "done",
+        }
+    }
+
+    fn into_sse_event(self, id: EventId) -> Event {
+        Event::default()
+            .id(id.to_string())
+            .event(self.name())
+            .json_data(&self)
+            .expect("Stream events serialize to JSON")
+    }
+
+    fn into_ndjson_line(self, id: EventId) -> String {
+        let line = NdjsonLine {
+            id,
+            kind: self.name(),
+            event: &self,
+        };
+        let mut line = serde_json::to_string(&line).expect("Stream events serialize to JSON");
+        line.push('\n');
+        line
+    }
+}
+
+// A line of an NDJSON stream: the event's payload, tagged with its type and id.
+#[derive(Serialize)]
+struct NdjsonLine<'a> {
+    #[serde(serialize_with = "serialize_display")]
+    id: EventId,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(flatten)]
+    event: &'a StreamEvent,
+}
+
+fn serialize_display<S: serde::Serializer>(
+    value: &impl std::fmt::Display,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+/// Marks a response whose body is streamed, so it is not buffered by the compression layer.
+#[derive(Debug, Clone, Copy)]
+pub struct Streamed;
+
+/// The wire format of a streaming response, negotiated from the `Accept` header.
+///
+/// Clients asking for `text/event-stream` get Server-Sent Events, and clients asking for
+/// `application/x-ndjson` get one JSON event per line. Everyone else gets the raw text chunks,
+/// which is what the `TextDecoderStream` based fallback reads. As raw text has no way to describe
+/// an error, the body is aborted instead, so the client's reader rejects rather than seeing a
+/// complete, truncated response.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamFormat {
+    EventStream,
+    Ndjson,
+    Text,
+}
+
+impl StreamFormat {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        if accepts(headers, EVENT_STREAM) {
+            StreamFormat::EventStream
+        } else if accepts(headers, NDJSON) {
+            StreamFormat::Ndjson
+        } else {
+            StreamFormat::Text
+        }
+    }
+
+    pub fn into_response(
+        self,
+        events: impl Stream<Item = (EventId, StreamEvent)> + Send + 'static,
+    ) -> Response {
+        let mut response = match self {
+            StreamFormat::EventStream => {
+                Sse::new(events.map(|(id, event)| Ok::<_, Infallible>(event.into_sse_event(id))))
+                    .keep_alive(KeepAlive::default())
+                    .into_response()
+            }
+            StreamFormat::Ndjson => {
+                let lines =
+                    events.map(|(id, event)| Ok::<_, Infallible>(event.into_ndjson_line(id)));
+
+                let headers = AppendHeaders([
+                    (header::CONTENT_TYPE, NDJSON),
+                    (header::CACHE_CONTROL, "no-cache"),
+                ]);
+
+                (headers, Body::from_stream(lines)).into_response()
+            }
+            StreamFormat::Text => {
+                let chunks = events.filter_map(|(_, event)| match event {
+                    StreamEvent::Chunk(chunk) => Some(Ok(chunk.text)),
+                    StreamEvent::Error(error) => Some(Err(io::Error::other(error.message))),
+                    _ => None,
+                });
+
+                let headers = AppendHeaders([
+                    (header::CONTENT_TYPE, "text/plain; charset=utf-8"),
+                    (header::CACHE_CONTROL, "no-cache"),
+                ]);
+
+                (headers, Body::from_stream(chunks)).into_response()
+            }
+        };
+        response.extensions_mut().insert(Streamed);
+        response
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for StreamFormat {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(StreamFormat::from_headers(&parts.headers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use built_in_hybrid_server::ai::language_model::UpstreamError;
+
+    #[test]
+    fn negotiates_event_stream_from_accept_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT,
+            "application/json, text/event-stream".parse().unwrap(),
+        );
+
+        assert_eq!(
+            StreamFormat::from_headers(&headers),
+            StreamFormat::EventStream
+        );
+    }
+
+    #[test]
+    fn negotiates_ndjson_from_accept_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, NDJSON.parse().unwrap());
+
+        assert_eq!(StreamFormat::from_headers(&headers), StreamFormat::Ndjson);
+    }
+
+    #[test]
+    fn ndjson_lines_carry_type_and_id() {
+        let id: EventId = "67e55044-10b1-426f-9247-bb680e5fe0c8:1".parse().unwrap();
+        let line = StreamEvent::chunk("Hello".to_string()).into_ndjson_line(id);
+
+        assert_eq!(
+            line,
+            "{\"id\":\"67e55044-10b1-426f-9247-bb680e5fe0c8:1\",\"type\":\"chunk\",\"text\":\"Hello\"}\n"
+        );
+    }
+
+    #[test]
+    fn defaults_to_raw_text() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(StreamFormat::from_headers(&headers), StreamFormat::Text);
+
+        headers.insert(header::ACCEPT, "*/*".parse().unwrap());
+        assert_eq!(StreamFormat::from_headers(&headers), StreamFormat::Text);
+    }
+
+    #[test]
+    fn serializes_events_as_json_payloads() {
+        let event = StreamEvent::chunk("Hello".to_string());
+
+        assert_eq!(event.name(), "chunk");
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"text":"Hello"}"#
+        );
+    }
+
+    #[test]
+    fn error_events_describe_delivered_output() {
+        let mut delivered = Delivered::default();
+        delivered.add("Hello, ");
+        delivered.add("wörld");
+
+        let error = AILanguageModelError::ProviderError(UpstreamError::new("Upstream failed"));
+        let event = StreamEvent::Error(ErrorEvent::new(&error, delivered));
+
+        assert_eq!(event.name(), "error");
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"code":"upstream_error","name":"UnknownError","message":"The model provider failed.","retryable":true,"delivered":{"chunks":2,"characters":12}}"#
+        );
+    }
+}
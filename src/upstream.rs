@@ -0,0 +1,594 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use built_in_hybrid_server::ai::language_model::{
+    AILanguageModelError, UpstreamError, deadline_exceeded,
+};
+
+/// How long a client is told to wait while a half-open circuit is probing the provider.
+const PROBE_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Guards the model providers during incidents: requests queue fairly for a limited number of
+/// slots, are shed when too many are waiting, and fail fast while a provider and model are
+/// failing, instead of piling up.
+#[derive(Debug)]
+pub struct Upstream {
+    breaker_config: BreakerConfig,
+    breakers: Mutex<HashMap<String, Arc<CircuitBreaker>>>,
+    queue: Arc<FairQueue>,
+}
+
+impl Upstream {
+    pub fn new(breaker_config: BreakerConfig, queue_config: QueueConfig) -> Self {
+        Upstream {
+            breaker_config,
+            breakers: Mutex::default(),
+            queue: Arc::new(FairQueue::new(queue_config)),
+        }
+    }
+
+    /// The circuit breaker of a provider's model.
+    pub fn breaker(&self, provider_id: &str, model_id: &str) -> Arc<CircuitBreaker> {
+        self.breakers
+            .lock()
+            .unwrap()
+            .entry(format!("{}/{}", provider_id, model_id))
+            .or_insert_with(|| Arc::new(CircuitBreaker::new(self.breaker_config)))
+            .clone()
+    }
+
+    /// Waits for a slot to call a provider, or refuses the request if it would not get one in
+    /// time. The slot is held until the returned [`Admission`] drops.
+    pub async fn admit(
+        &self,
+        client: &ClientId,
+        deadline: tokio::time::Instant,
+    ) -> Result<Admission, AILanguageModelError> {
+        self.queue.admit(client, deadline).await
+    }
+
+    /// How busy the providers are: the requests running and waiting, as a share of the requests
+    /// that can run at once. Over 1 when requests are waiting.
+    pub fn load(&self) -> f64 {
+        let state = self.queue.state.lock().unwrap();
+        (state.running + state.queued) as f64 / self.queue.config.max_concurrency.max(1) as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    /// How many failures within `window` open the circuit.
+    pub failure_threshold: usize,
+    pub window: Duration,
+    /// Successful calls slower than this count as failures.
+    pub latency_slo: Duration,
+    /// How long the circuit stays open before probing the provider again.
+    pub open_duration: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            failure_threshold: 5,
+            window: Duration::from_secs(30),
+            latency_slo: Duration::from_secs(20),
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Stops calling a provider after a burst of failures or slow calls. Once `open_duration` has
+/// passed, a single probe request is let through: the circuit closes if it succeeds, and opens
+/// again if it fails.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug)]
+enum BreakerState {
+    Closed { failures: VecDeque<Instant> },
+    Open { until: Instant },
+    HalfOpen { probing: bool },
+}
+
+impl CircuitBreaker {
+    fn new(config: BreakerConfig) -> Self {
+        CircuitBreaker {
+            config,
+            state: Mutex::new(BreakerState::Closed {
+                failures: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Runs a provider call through the breaker, failing fast while the circuit is open.
+    pub async fn call<T>(
+        &self,
+        call: impl Future<Output = Result<T, AILanguageModelError>>,
+    ) -> Result<T, AILanguageModelError> {
+        let mut permit = self.acquire()?;
+        let started = Instant::now();
+        let result = call.await;
+
+        let failed = match &result {
+            Ok(_) => started.elapsed() > self.config.latency_slo,
+            Err(err) => counts_as_failure(err),
+        };
+        permit.record(failed);
+        result
+    }
+
+    /// Records a failure that happened after a call succeeded, like a stream breaking midway.
+    pub fn record_failure(&self, err: &AILanguageModelError) {
+        if counts_as_failure(err) {
+            self.record(false, true);
+        }
+    }
+
+    fn acquire(&self) -> Result<Permit<'_>, AILanguageModelError> {
+        let mut state = self.state.lock().unwrap();
+        let probe = match *state {
+            BreakerState::Closed { .. } => false,
+            BreakerState::Open { until } => {
+                let now = Instant::now();
+                if now < until {
+                    return Err(circuit_open(until - now));
+                }
+                info!("Circuit half-open, probing the provider.");
+                *state = BreakerState::HalfOpen { probing: true };
+                true
+            }
+            BreakerState::HalfOpen { probing: true } => {
+                return Err(circuit_open(PROBE_RETRY_AFTER));
+            }
+            BreakerState::HalfOpen { probing: false } => {
+                *state = BreakerState::HalfOpen { probing: true };
+                true
+            }
+        };
+
+        Ok(Permit {
+            breaker: self,
+            probe,
+            recorded: false,
+        })
+    }
+
+    fn record(&self, probe: bool, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match &mut *state {
+            BreakerState::Closed { failures } if failed => {
+                failures.push_back(now);
+                while failures
+                    .front()
+                    .is_some_and(|failure| now.duration_since(*failure) > self.config.window)
+                {
+                    failures.pop_front();
+                }
+                if failures.len() >= self.config.failure_threshold {
+                    warn!(failures = failures.len(), "Circuit opened.");
+                    *state = BreakerState::Open {
+                        until: now + self.config.open_duration,
+                    };
+                }
+            }
+            BreakerState::HalfOpen { .. } if probe && failed => {
+                warn!("Probe failed, circuit opened again.");
+                *state = BreakerState::Open {
+                    until: now + self.config.open_duration,
+                };
+            }
+            BreakerState::HalfOpen { .. } if probe => {
+                info!("Probe succeeded, circuit closed.");
+                *state = BreakerState::Closed {
+                    failures: VecDeque::new(),
+                };
+            }
+            // Calls that started before the circuit opened don't change it.
+            _ => {}
+        }
+    }
+}
+
+// Lets a call through the breaker. A probe that is dropped without a result, because its request
+// was cancelled, gives the next request the chance to probe.
+struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    fn record(&mut self, failed: bool) {
+        self.recorded = true;
+        self.breaker.record(self.probe, failed);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.probe && !self.recorded {
+            let mut state = self.breaker.state.lock().unwrap();
+            if let BreakerState::HalfOpen { probing } = &mut *state {
+                *probing = false;
+            }
+        }
+    }
+}
+
+// Errors caused by the request itself say nothing about the provider's health.
+fn counts_as_failure(err: &AILanguageModelError) -> bool {
+    err.is_retryable()
+}
+
+fn circuit_open(retry_after: Duration) -> AILanguageModelError {
+    AILanguageModelError::OverloadedError(
+        UpstreamError::new("The model provider is failing, try again later.")
+            .with_retry_after(retry_after),
+    )
+}
+
+/// Identifies the client a request comes from, so that queued requests are served fairly across
+/// clients.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientId(pub String);
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    /// How many provider calls run at once.
+    pub max_concurrency: usize,
+    /// How many requests may wait for a slot, across all clients.
+    pub max_queued: usize,
+    /// How many requests of a single client may wait for a slot.
+    pub max_queued_per_client: usize,
+    /// How long a request may wait for a slot before it expires.
+    pub max_wait: Duration,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            max_concurrency: 64,
+            max_queued: 256,
+            max_queued_per_client: 32,
+            max_wait: Duration::from_secs(30),
+        }
+    }
+}
+
+// Limits the provider calls in flight, queueing the requests over the limit. Each client has a
+// queue of its own, and freed slots go to the clients in turn, so a client sending many requests
+// only delays its own. Requests are refused when their client's queue or the overall queue is
+// full, and shed when the expected wait, estimated from how long recent requests held their slot,
+// would outlast their deadline. Failing them right away beats failing them once the deadline
+// passes.
+#[derive(Debug)]
+struct FairQueue {
+    config: QueueConfig,
+    state: Mutex<QueueState>,
+    hold_time: Mutex<Duration>,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    running: usize,
+    queued: usize,
+    next_waiter: u64,
+    waiting: HashMap<ClientId, VecDeque<(u64, oneshot::Sender<()>)>>,
+    // The clients with waiting requests, in the order they get the next slots.
+    turns: VecDeque<ClientId>,
+}
+
+impl FairQueue {
+    fn new(config: QueueConfig) -> Self {
+        FairQueue {
+            config,
+            state: Mutex::default(),
+            hold_time: Mutex::new(Duration::from_secs(1)),
+        }
+    }
+
+    async fn admit(
+        self: &Arc<Self>,
+        client: &ClientId,
+        deadline: tokio::time::Instant,
+    ) -> Result<Admission, AILanguageModelError> {
+        let mut waiter = {
+            let mut state = self.state.lock().unwrap();
+            if state.running < self.config.max_concurrency.max(1) {
+                state.running += 1;
+                return Ok(self.admission());
+            }
+
+            let client_queued = state.waiting.get(client).map_or(0, VecDeque::len);
+            let clients = state.turns.len() + usize::from(client_queued == 0);
+            let expected_wait =
+                self.expected_wait(((client_queued + 1) * clients).min(state.queued + 1));
+            if state.queued >= self.config.max_queued
+                || client_queued >= self.config.max_queued_per_client
+            {
+                return Err(queue_full(expected_wait));
+            }
+            if tokio::time::Instant::now() + expected_wait > deadline {
+                return Err(shed(expected_wait));
+            }
+
+            let id = state.next_waiter;
+            state.next_waiter += 1;
+            state.queued += 1;
+            if client_queued == 0 {
+                state.turns.push_back(client.clone());
+            }
+            let (tx, rx) = oneshot::channel();
+            state
+                .waiting
+                .entry(client.clone())
+                .or_default()
+                .push_back((id, tx));
+
+            Waiter {
+                queue: self,
+                client,
+                id,
+                rx,
+                admitted: false,
+            }
+        };
+
+        let expires_at = deadline.min(tokio::time::Instant::now() + self.config.max_wait);
+        match tokio::time::timeout_at(expires_at, &mut waiter.rx).await {
+            Ok(Ok(())) => {
+                waiter.admitted = true;
+                Ok(self.admission())
+            }
+            Ok(Err(_)) => unreachable!("Waiters are only dropped by handing them a slot"),
+            Err(_) if expires_at == deadline => Err(deadline_exceeded()),
+            Err(_) => Err(expired(self.config.max_wait)),
+        }
+    }
+
+    // How long a request waits, with `ahead` requests served before it.
+    fn expected_wait(&self, ahead: usize) -> Duration {
+        *self.hold_time.lock().unwrap() * ahead as u32 / self.config.max_concurrency.max(1) as u32
+    }
+
+    fn admission(self: &Arc<Self>) -> Admission {
+        Admission {
+            queue: self.clone(),
+            admitted_at: Instant::now(),
+        }
+    }
+
+    // Hands a freed slot to the next client in turn, or frees it if nobody is waiting.
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(client) = state.turns.pop_front() {
+            let queue = state
+                .waiting
+                .get_mut(&client)
+                .expect("Clients in turn have waiting requests");
+            let (_, tx) = queue.pop_front().expect("Client queues are never empty");
+            if queue.is_empty() {
+                state.waiting.remove(&client);
+            } else {
+                state.turns.push_back(client);
+            }
+            state.queued -= 1;
+
+            if tx.send(()).is_ok() {
+                return;
+            }
+        }
+        state.running -= 1;
+    }
+}
+
+// A request waiting for a slot. Dropping it before it is admitted, because the request expired or
+// was cancelled, leaves the queue.
+struct Waiter<'a> {
+    queue: &'a FairQueue,
+    client: &'a ClientId,
+    id: u64,
+    rx: oneshot::Receiver<()>,
+    admitted: bool,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        if self.admitted {
+            return;
+        }
+
+        {
+            let mut state = self.queue.state.lock().unwrap();
+            if let Some(queue) = state.waiting.get_mut(self.client)
+                && let Some(index) = queue.iter().position(|(id, _)| *id == self.id)
+            {
+                queue.remove(index);
+                if queue.is_empty() {
+                    state.waiting.remove(self.client);
+                    state.turns.retain(|client| client != self.client);
+                }
+                state.queued -= 1;
+                return;
+            }
+        }
+
+        // The slot was handed over as the request gave up waiting, so pass it on.
+        if self.rx.try_recv().is_ok() {
+            self.queue.release();
+        }
+    }
+}
+
+fn queue_full(expected_wait: Duration) -> AILanguageModelError {
+    AILanguageModelError::TooManyRequestsError(
+        UpstreamError::new("Too many requests are waiting, try again later.")
+            .with_retry_after(expected_wait.max(Duration::from_secs(1))),
+    )
+}
+
+fn shed(expected_wait: Duration) -> AILanguageModelError {
+    AILanguageModelError::OverloadedError(
+        UpstreamError::new("The server is overloaded, try again later.")
+            .with_retry_after(expected_wait.max(Duration::from_secs(1))),
+    )
+}
+
+fn expired(max_wait: Duration) -> AILanguageModelError {
+    AILanguageModelError::OverloadedError(
+        UpstreamError::new("The request waited too long for the model provider.")
+            .with_retry_after(max_wait),
+    )
+}
+
+/// A slot to call a provider. It is handed to the next waiting request when dropped.
+#[derive(Debug)]
+pub struct Admission {
+    queue: Arc<FairQueue>,
+    admitted_at: Instant,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        // An exponentially weighted moving average, so the estimate follows the provider's latency.
+        {
+            let mut hold_time = self.queue.hold_time.lock().unwrap();
+            *hold_time = (*hold_time * 4 + self.admitted_at.elapsed()) / 5;
+        }
+        self.queue.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: BreakerConfig = BreakerConfig {
+        failure_threshold: 2,
+        window: Duration::from_secs(30),
+        latency_slo: Duration::from_secs(20),
+        open_duration: Duration::from_secs(30),
+    };
+
+    fn unavailable() -> AILanguageModelError {
+        AILanguageModelError::UnavailableError(UpstreamError::new("Unavailable"))
+    }
+
+    #[tokio::test]
+    async fn opens_after_a_burst_of_failures() {
+        let breaker = CircuitBreaker::new(CONFIG);
+
+        for _ in 0..2 {
+            let result: Result<(), _> = breaker.call(async { Err(unavailable()) }).await;
+            assert!(matches!(
+                result,
+                Err(AILanguageModelError::UnavailableError(_))
+            ));
+        }
+
+        let result = breaker.call(async { Ok(()) }).await;
+        let Err(AILanguageModelError::OverloadedError(err)) = result else {
+            panic!("Expected the circuit to be open, got {:?}", result);
+        };
+        assert!(err.retry_after.is_some());
+    }
+
+    #[tokio::test]
+    async fn ignores_errors_caused_by_the_request() {
+        let breaker = CircuitBreaker::new(CONFIG);
+
+        for _ in 0..3 {
+            let _: Result<(), _> = breaker
+                .call(async { Err(AILanguageModelError::PromptInputError("Invalid")) })
+                .await;
+        }
+
+        assert!(breaker.call(async { Ok(()) }).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn closes_after_a_successful_probe() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            open_duration: Duration::ZERO,
+            ..CONFIG
+        });
+        breaker.record_failure(&unavailable());
+        breaker.record_failure(&unavailable());
+        assert!(matches!(
+            *breaker.state.lock().unwrap(),
+            BreakerState::Open { .. }
+        ));
+
+        assert!(breaker.call(async { Ok(()) }).await.is_ok());
+        assert!(matches!(
+            *breaker.state.lock().unwrap(),
+            BreakerState::Closed { .. }
+        ));
+    }
+
+    fn client(name: &str) -> ClientId {
+        ClientId(name.to_string())
+    }
+
+    fn queue_config(max_queued_per_client: usize) -> QueueConfig {
+        QueueConfig {
+            max_concurrency: 1,
+            max_queued: 16,
+            max_queued_per_client,
+            max_wait: Duration::from_secs(30),
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_when_the_client_queue_is_full() {
+        let upstream = Upstream::new(CONFIG, queue_config(0));
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(60);
+
+        let admission = upstream.admit(&client("a"), deadline).await.unwrap();
+        let result = upstream.admit(&client("a"), deadline).await;
+        let Err(AILanguageModelError::TooManyRequestsError(err)) = result else {
+            panic!("Expected the request to be refused, got {:?}", result);
+        };
+        assert!(err.retry_after.is_some());
+
+        drop(admission);
+        assert!(upstream.admit(&client("a"), deadline).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn serves_clients_in_turn() {
+        let upstream = Arc::new(Upstream::new(CONFIG, queue_config(4)));
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(60);
+        let admission = upstream.admit(&client("noisy"), deadline).await.unwrap();
+
+        let (order_tx, mut order_rx) = tokio::sync::mpsc::unbounded_channel();
+        for name in ["noisy", "noisy", "noisy", "quiet"] {
+            let upstream = upstream.clone();
+            let order_tx = order_tx.clone();
+            tokio::spawn(async move {
+                let _admission = upstream.admit(&client(name), deadline).await.unwrap();
+                order_tx.send(name).unwrap();
+            });
+            // Let the request join the queue before the next one.
+            tokio::task::yield_now().await;
+        }
+        drop(order_tx);
+        drop(admission);
+
+        let mut order = Vec::new();
+        while let Some(name) = order_rx.recv().await {
+            order.push(name);
+        }
+        assert_eq!(order, vec!["noisy", "quiet", "noisy", "noisy"]);
+    }
+}
@@ -1,3 +1,97 @@
+// The client token the site's backend issued to the page, when the server requires one.
+let clientToken = null;
+
+export function setClientToken(token) {
+    clientToken = token;
+}
+
+function withClientToken(headers = {}) {
+    return clientToken ? { ...headers, 'X-Client-Token': clientToken } : headers;
+}
+
+// The proof-of-work allowance anonymous prompts are paid with, when the server asks for one.
+let allowance = null;
+
+// Sends a prompt request with the page's allowance. When the server wants a new one, solves a
+// challenge to earn it and sends the request again.
+async function promptFetch(url, init) {
+    const send = () => fetch(url, {
+        ...init,
+        headers: allowance ? { ...init.headers, 'X-Pow-Allowance': allowance } : init.headers,
+    });
+
+    const response = await send();
+    if (response.status !== 401 || !(await isChallengeRequired(response))) {
+        return response;
+    }
+    await earnAllowance(init.signal);
+    return send();
+}
+
+async function isChallengeRequired(response) {
+    try {
+        const { error } = await response.clone().json();
+        return error.code === 'challenge_required';
+    } catch {
+        return false;
+    }
+}
+
+async function earnAllowance(signal) {
+    const issued = await fetch('/language-model/challenge', {
+        method: 'POST',
+        signal,
+        headers: withClientToken(),
+    });
+    if (!issued.ok) {
+        throw await responseError(issued);
+    }
+    const { challenge, difficulty } = await issued.json();
+
+    const solution = await solveChallenge(challenge, difficulty, signal);
+    const solved = await fetch('/language-model/challenge/solve', {
+        method: 'POST',
+        signal,
+        headers: withClientToken({
+            'Content-Type': 'application/json'
+        }),
+        body: JSON.stringify({ challenge, solution }),
+    });
+    if (!solved.ok) {
+        throw await responseError(solved);
+    }
+    ({ allowance } = await solved.json());
+}
+
+// Finds a counter whose SHA-256 hash of `<challenge>:<counter>` starts with `difficulty` zero bits.
+async function solveChallenge(challenge, difficulty, signal) {
+    const encoder = new TextEncoder();
+    for (let counter = 0; ; counter++) {
+        if (counter % 1000 === 0) {
+            signal?.throwIfAborted();
+        }
+        const solution = counter.toString();
+        const hash = await crypto.subtle.digest(
+            'SHA-256',
+            encoder.encode(`${challenge}:${solution}`),
+        );
+        if (leadingZeroBits(new Uint8Array(hash)) >= difficulty) {
+            return solution;
+        }
+    }
+}
+
+function leadingZeroBits(bytes) {
+    let bits = 0;
+    for (const byte of bytes) {
+        bits += Math.clz32(byte) - 24;
+        if (byte !== 0) {
+            break;
+        }
+    }
+    return bits;
+}
+
 export class FallbackLanguageModel extends EventTarget {
     constructor(createOptions, capabilities) {
         super();
@@ -9,7 +103,10 @@ export class FallbackLanguageModel extends EventTarget {
     }
 
     static async create(options) {
-        const response = await fetch('/language-model/capabilities', { method: 'POST' });
+        const response = await fetch('/language-model/capabilities', {
+            method: 'POST',
+            headers: withClientToken(),
+        });
         if (!response.ok) {
             throw new Error(`HTTP error! status: ${result.status} / ${result.statusText}`);
         }
@@ -20,9 +117,16 @@ export class FallbackLanguageModel extends EventTarget {
         const capabilities = await response.json();
         console.info('capabilities:', capabilities);
         
+        // Like the built-in API, take both or neither; the server defaults and clamps them.
+        if ((options.temperature === undefined) !== (options.topK === undefined)) {
+            throw new DOMException(
+                'Initializing a new session must either specify both topK and temperature, or neither of them.',
+                'NotSupportedError',
+            );
+        }
         const createOptions = {
-            temperature: options.temperature || capabilities.defaultTemperature,
-            topK: options.topK || capabilities.defaultTopK,
+            temperature: options.temperature,
+            topK: options.topK,
             systemPrompt: options.systemPrompt || null,
             expectedInputs: options.expectedInputs || [],
             initialPrompts: options.initialPrompts || [],
@@ -32,13 +136,14 @@ export class FallbackLanguageModel extends EventTarget {
         return new FallbackLanguageModel(createOptions, capabilities);
     }
 
-    async prompt(input) {
+    async prompt(input, options = {}) {
         const inputs = normalizeInputs(input);
-        const result = await fetch('/language-model/prompt', {
+        const result = await promptFetch('/language-model/prompt', {
             method: 'POST',
-            headers: {
+            signal: options.signal,
+            headers: withClientToken({
                 'Content-Type': 'application/json'
-            },
+            }),
             body: JSON.stringify({
                 createOptions: this.createOptions,
                 inputs: inputs,
@@ -46,7 +151,7 @@ export class FallbackLanguageModel extends EventTarget {
         });
 
         if (!result.ok) {
-            throw new Error(`HTTP error! status: ${result.status} / ${result.statusText}`);
+            throw await responseError(result);
         }
         if (!result.body) {
             throw new Error('Response body is null');
@@ -55,13 +160,15 @@ export class FallbackLanguageModel extends EventTarget {
         return result.text();
     }
 
-    async promptStreaming(input) {
+    async promptStreaming(input, options = {}) {
         const inputs = normalizeInputs(input);
-        const result = await fetch('/language-model/prompt-streaming', {
+        const result = await promptFetch('/language-model/prompt-streaming', {
             method: 'POST',
-            headers: {
-                'Content-Type': 'application/json'
-            },
+            signal: options.signal,
+            headers: withClientToken({
+                'Content-Type': 'application/json',
+                'Accept': 'text/event-stream',
+            }),
             body: JSON.stringify({
                 createOptions: this.createOptions,
                 inputs: inputs,
@@ -69,22 +176,34 @@ export class FallbackLanguageModel extends EventTarget {
         });
 
         if (!result.ok) {
-            throw new Error(`HTTP error! status: ${result.status}`);
+            throw await responseError(result);
         }
         if (!result.body) {
             throw new Error('Response body is null');
         }
 
-        return result.body.pipeThrough(new TextDecoderStream())
+        // Aborting the fetch closes the connection, but also cancel the generation explicitly so
+        // the server stops it right away.
+        const generationId = result.headers.get('X-Generation-Id');
+        options.signal?.addEventListener('abort', () => {
+            fetch(`/language-model/generations/${generationId}/cancel`, {
+                method: 'POST',
+                headers: withClientToken(),
+            })
+                .catch(error => console.warn('Failed to cancel generation:', error));
+        }, { once: true });
+
+        return resumableEvents(result, generationId, options.signal)
+            .pipeThrough(promptEventsToText());
     }
 
     async countTokens(input) { // Changed parameter name from 'inputs' to 'input'
         const normalizedInputs = normalizeInputs(input); // Use a different variable name
         const result = await fetch('/language-model/count-tokens', {
             method: 'POST',
-            headers: {
+            headers: withClientToken({
                 'Content-Type': 'application/json'
-            },
+            }),
             body: JSON.stringify({
                 createOptions: this.createOptions,
                 inputs: normalizedInputs, // Use the normalized inputs
@@ -92,7 +211,7 @@ export class FallbackLanguageModel extends EventTarget {
         });
 
         if (!result.ok) {
-            throw new Error(`HTTP error! status: ${result.status}`);
+            throw await responseError(result);
         }
         if (!result.body) {
             throw new Error('Response body is null');
@@ -102,6 +221,139 @@ export class FallbackLanguageModel extends EventTarget {
     }
 }
 
+// Turns an error response into the DOMException the built-in API would throw.
+async function responseError(response) {
+    try {
+        const { error } = await response.json();
+        return new DOMException(error.message, error.name);
+    } catch {
+        return new Error(`HTTP error! status: ${response.status} / ${response.statusText}`);
+    }
+}
+
+const MAX_RESUMES = 3;
+
+// Reads the events of a streaming response. When the connection drops, the generation is resumed
+// from the last event received, so the server replays what was missed instead of starting over.
+function resumableEvents(response, generationId, signal) {
+    let lastEventId = `${generationId}:0`;
+    let resumes = 0;
+    let reader = readEvents(response);
+
+    return new ReadableStream({
+        async pull(controller) {
+            while (true) {
+                try {
+                    const { done, value } = await reader.read();
+                    if (done) {
+                        controller.close();
+                    } else {
+                        lastEventId = value.id || lastEventId;
+                        controller.enqueue(value);
+                    }
+                    return;
+                } catch (error) {
+                    // Network failures reject with a TypeError. Anything else is not resumable.
+                    if (signal?.aborted || !(error instanceof TypeError) || resumes >= MAX_RESUMES) {
+                        throw error;
+                    }
+                    resumes++;
+                    console.warn(`Connection lost, resuming after ${lastEventId}:`, error);
+                    const resumed = await fetch('/language-model/prompt-streaming/resume', {
+                        signal,
+                        headers: withClientToken({
+                            'Accept': 'text/event-stream',
+                            'Last-Event-ID': lastEventId,
+                        }),
+                    });
+                    if (!resumed.ok || !resumed.body) {
+                        throw error;
+                    }
+                    reader = readEvents(resumed);
+                }
+            }
+        },
+        cancel(reason) {
+            return reader.cancel(reason);
+        },
+    });
+}
+
+function readEvents(response) {
+    return response.body
+        .pipeThrough(new TextDecoderStream())
+        .pipeThrough(parseEventStream())
+        .getReader();
+}
+
+// Splits a Server-Sent Events stream into `{ id, event, data }` messages with parsed JSON data.
+function parseEventStream() {
+    let buffer = '';
+    return new TransformStream({
+        transform(chunk, controller) {
+            buffer += chunk;
+            const messages = buffer.split(/\r?\n\r?\n/);
+            buffer = messages.pop();
+            for (const message of messages) {
+                let id = null;
+                let event = 'message';
+                const data = [];
+                for (const line of message.split(/\r?\n/)) {
+                    if (line.startsWith(':')) {
+                        continue; // Keep-alive comment.
+                    }
+                    const separator = line.indexOf(':');
+                    const field = separator === -1 ? line : line.slice(0, separator);
+                    const value = separator === -1 ? '' : line.slice(separator + 1).replace(/^ /, '');
+                    if (field === 'id') {
+                        id = value;
+                    } else if (field === 'event') {
+                        event = value;
+                    } else if (field === 'data') {
+                        data.push(value);
+                    }
+                }
+                if (data.length > 0) {
+                    controller.enqueue({ id, event, data: JSON.parse(data.join('\n')) });
+                }
+            }
+        },
+    });
+}
+
+// Turns prompt stream events into response text. Errors reject the reader with the same
+// DOMException the built-in API would throw, and so does a stream that ends without `done` or
+// whose response the safety filters blocked.
+function promptEventsToText() {
+    let done = false;
+    return new TransformStream({
+        transform({ event, data }, controller) {
+            switch (event) {
+                case 'chunk':
+                    controller.enqueue(data.text);
+                    break;
+                case 'error':
+                    console.error('Prompt stream error:', data);
+                    controller.error(new DOMException(data.message, data.name));
+                    break;
+                case 'done':
+                    done = true;
+                    if (data.blockReason) {
+                        controller.error(new DOMException(
+                            `The response was blocked by the safety filters (${data.blockReason}).`,
+                            'NotAllowedError'));
+                    }
+                    break;
+            }
+        },
+        flush(controller) {
+            if (!done) {
+                controller.error(new DOMException('The response stream ended unexpectedly.', 'NetworkError'));
+            }
+        },
+    });
+}
+
 function normalizeInputs(input) {
     const inputs = [];
     if (typeof input === 'string') {
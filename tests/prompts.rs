@@ -6,6 +6,7 @@ pub fn test_prompt_text() {
     let prompt = AILanguageModelPrompt::Text {
         role: AILanguageModelPromptRole::default(),
         content: "Hello, world!".to_string(),
+        prefix: false,
     };
 
     assert_eq!(